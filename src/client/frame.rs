@@ -0,0 +1,167 @@
+use types::message::{MessageIn, FrameKind, Object};
+
+use rustc_serialize::json::ToJson;
+
+//  Builds the attributes of a single object, anything not covered by
+//  the typed setters can be added with attribute()
+pub struct ObjectBuilder {
+    attributes: Object,
+}
+
+impl ObjectBuilder {
+    //  The type selects the model, color and texture in the configuration
+    pub fn new(type_name: &str) -> ObjectBuilder {
+        ObjectBuilder {
+            attributes: Object::new(),
+        }.attribute("type", type_name)
+    }
+
+    //  Changes the attributes of an object from a previous frame, for delta frames
+    pub fn update(id: u32) -> ObjectBuilder {
+        ObjectBuilder {
+            attributes: Object::new(),
+        }.id(id)
+    }
+
+    pub fn id(self, id: u32) -> ObjectBuilder {
+        self.attribute("id", &id)
+    }
+
+    //  Permanent objects stay in the scene until they are removed explicitly
+    pub fn permanent_id(self, id: u32) -> ObjectBuilder {
+        self.attribute("permanent_id", &id)
+    }
+
+    pub fn position(self, x: f32, y: f32, z: f32) -> ObjectBuilder {
+        self.attribute("x", &x).attribute("y", &y).attribute("z", &z)
+    }
+
+    pub fn attribute<T: ToJson + ?Sized>(mut self, name: &str, value: &T) -> ObjectBuilder {
+        self.attributes.insert(String::from(name), value.to_json());
+        self
+    }
+
+    pub fn build(self) -> Object {
+        self.attributes
+    }
+}
+
+//  A frame without its publisher, which is filled in by the connection sending it
+pub struct Frame {
+    message: MessageIn,
+}
+
+impl Frame {
+    //  Replaces the whole scene
+    pub fn keyframe<I: ToString>(id: I) -> Frame {
+        Frame::new(id.to_string(), FrameKind::Keyframe)
+    }
+
+    //  Updates the objects by id, keeping the rest of the scene
+    pub fn delta<I: ToString>(id: I) -> Frame {
+        Frame::new(id.to_string(), FrameKind::Delta)
+    }
+
+    fn new(id: String, kind: FrameKind) -> Frame {
+        Frame {
+            message: MessageIn {
+                publisher:         String::new(),
+                id:                id,
+                kind:              kind,
+                objects:           vec![],
+                removed:           vec![],
+                removed_permanent: vec![],
+                clear_permanent:   false,
+                new_game:          false,
+            },
+        }
+    }
+
+    pub fn object(mut self, object: ObjectBuilder) -> Frame {
+        self.message.objects.push(object.build());
+        self
+    }
+
+    pub fn remove(mut self, id: u32) -> Frame {
+        self.message.removed.push(id);
+        self
+    }
+
+    pub fn remove_permanent(mut self, id: u32) -> Frame {
+        self.message.removed_permanent.push(id);
+        self
+    }
+
+    pub fn clear_permanent(mut self) -> Frame {
+        self.message.clear_permanent = true;
+        self
+    }
+
+    pub fn new_game(mut self) -> Frame {
+        self.message.new_game = true;
+        self
+    }
+
+    pub fn into_message(mut self, publisher: &str) -> MessageIn {
+        self.message.publisher = String::from(publisher);
+        self.message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::json::Json;
+
+    //  What the server makes of the frame
+    fn round_trip(frame: Frame) -> MessageIn {
+        MessageIn::from_json(frame.into_message("p").to_json(), None).unwrap()
+    }
+
+    #[test]
+    fn keyframe() {
+        let msg = round_trip(Frame::keyframe(7)
+            .object(ObjectBuilder::new("unit").id(1).position(1.5, -2.0, 0.0).attribute("hp", &10))
+            .object(ObjectBuilder::new("wall").permanent_id(3).attribute("name", "north")));
+
+        assert_eq!(msg.publisher, "p");
+        assert_eq!(msg.id, "7");
+        assert_eq!(msg.kind, FrameKind::Keyframe);
+        assert_eq!(msg.objects.len(), 2);
+        assert_eq!(msg.objects[0].get("type"), Some(&Json::String(String::from("unit"))));
+        assert_eq!(msg.objects[0].get("id"), Some(&Json::U64(1)));
+        assert_eq!(msg.objects[0].get("x"), Some(&Json::F64(1.5)));
+        assert_eq!(msg.objects[0].get("y"), Some(&Json::F64(-2.0)));
+        assert_eq!(msg.objects[0].get("hp"), Some(&Json::I64(10)));
+        assert_eq!(msg.objects[1].get("permanent_id"), Some(&Json::U64(3)));
+        assert_eq!(msg.objects[1].get("name"), Some(&Json::String(String::from("north"))));
+        assert!(msg.removed.is_empty());
+        assert!(!msg.new_game);
+    }
+
+    #[test]
+    fn delta() {
+        let msg = round_trip(Frame::delta("step-2")
+            .object(ObjectBuilder::update(1).attribute("hp", &9))
+            .remove(2)
+            .remove(4));
+
+        assert_eq!(msg.id, "step-2");
+        assert_eq!(msg.kind, FrameKind::Delta);
+        assert_eq!(msg.objects.len(), 1);
+        assert_eq!(msg.objects[0].len(), 2);
+        assert_eq!(msg.removed, vec![2, 4]);
+        assert!(msg.removed_permanent.is_empty());
+        assert!(!msg.clear_permanent);
+    }
+
+    #[test]
+    fn permanent_removal_and_new_game() {
+        let msg = round_trip(Frame::delta(3).remove_permanent(5).clear_permanent().new_game());
+
+        assert_eq!(msg.removed_permanent, vec![5]);
+        assert!(msg.clear_permanent);
+        assert!(msg.new_game);
+        assert!(msg.objects.is_empty());
+    }
+}
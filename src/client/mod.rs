@@ -0,0 +1,7 @@
+//  Publisher side of the protocol, for programs sending their state to the server
+mod frame;
+mod publisher;
+
+pub use self::frame::{Frame, ObjectBuilder};
+pub use self::publisher::{Publisher, Events, Event};
+pub use types::message::{MessageIn, MessageOut, MessageError, Welcome, FrameKind, EventKind, Modifiers, Object};
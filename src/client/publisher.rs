@@ -0,0 +1,202 @@
+use types::message::{MessageOut, MessageError, Reply, Hello, Welcome, EventKind, PROTOCOL_VERSION};
+use client::frame::Frame;
+
+use std::thread;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs, Shutdown};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::time::Duration;
+use rustc_serialize::json;
+use rustc_serialize::json::{Json, ToJson};
+
+//  Asked for in the hello, input events are needed for the lockstep steps
+const FEATURES: &'static [&'static str] = &["errors", "typed-values", "delta", "permanent-removal", "tokens", "heartbeat", "input-events"];
+
+//  Servers from before the hello don't answer it at all
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+//  Input events of the visualizations, or errors concerning the sent frames
+pub type Event = Result<MessageOut, MessageError>;
+
+//  A connection publishing frames under a single name
+pub struct Publisher {
+    name: String,
+    stream: TcpStream,
+    welcome: Welcome,
+    events: Receiver<Event>,
+    //  Received while waiting for a step, not seen by the caller yet
+    pending: VecDeque<Event>,
+}
+
+impl Publisher {
+    pub fn connect<A: ToSocketAddrs>(address: A, name: &str) -> Result<Publisher, String> {
+        Publisher::connect_with_token(address, name, None)
+    }
+
+    //  Publishers listed in the tokens file of the server have to present their token
+    pub fn connect_with_token<A: ToSocketAddrs>(address: A, name: &str, token: Option<&str>) -> Result<Publisher, String> {
+        let mut stream = TcpStream::connect(address)
+            .map_err(|error| format!("Could not connect: {}", error))?;
+
+        let hello = Hello {
+            version:   PROTOCOL_VERSION,
+            publisher: Some(String::from(name)),
+            encoding:  Some(String::from("json")),
+            features:  Some(FEATURES.iter().map(|feature| String::from(*feature)).collect()),
+            token:     token.map(String::from),
+        };
+
+        //  Every frame and every reply takes a single line
+        let handshake = format!("FRAMING ndjson\n{{\"hello\":{}}}\n", json::as_json(&hello));
+        stream.write_all(handshake.as_bytes())
+            .map_err(|error| format!("Could not send the hello: {}", error))?;
+
+        let mut reader = match stream.try_clone() {
+            Ok(stream) => BufReader::new(stream),
+            Err(error) => return Err(format!("Could not set up the connection: {}", error)),
+        };
+
+        //  The clone shares the socket, and with it the timeout
+        stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))
+            .map_err(|error| format!("Could not set up the connection: {}", error))?;
+
+        let welcome = match read_reply(&mut reader) {
+            Ok(Some(Reply::Welcome(welcome))) => welcome,
+            Ok(Some(Reply::Error(error)))     => return Err(format!("Server refused the connection: {}", error.error)),
+            Ok(Some(Reply::Event(_)))         => return Err(String::from("Server didn't answer the hello")),
+            Ok(None)                          => return Err(String::from("Server closed the connection")),
+            Err(error)                        => return Err(format!("Server didn't answer the hello ({})", error)),
+        };
+
+        stream.set_read_timeout(None)
+            .map_err(|error| format!("Could not set up the connection: {}", error))?;
+
+        let (link, events) = mpsc::channel::<Event>();
+        thread::spawn(move || {
+            Publisher::read_replies(reader, link);
+        });
+
+        Ok(Publisher {
+            name:    String::from(name),
+            stream:  stream,
+            welcome: welcome,
+            events:  events,
+            pending: VecDeque::<Event>::new(),
+        })
+    }
+
+    fn read_replies<R: BufRead>(mut reader: R, link: Sender<Event>) {
+        loop {
+            let event = match read_reply(&mut reader) {
+                Ok(Some(Reply::Event(msg)))   => Ok(msg),
+                Ok(Some(Reply::Error(error))) => Err(error),
+                Ok(Some(Reply::Welcome(_)))   => continue,
+                Ok(None)                      => break,
+                Err(error)                    => {
+                    println!("(Publisher) {}", error);
+                    break;
+                },
+            };
+
+            if link.send(event).is_err() {
+                break;
+            }
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    //  Protocol version and features agreed on with the server
+    pub fn welcome(&self) -> &Welcome {
+        &self.welcome
+    }
+
+    pub fn send(&mut self, frame: Frame) -> Result<(), String> {
+        let msg = frame.into_message(&self.name);
+        let line = format!("{}\n", msg.to_json());
+
+        self.stream.write_all(line.as_bytes())
+            .map_err(|error| format!("Could not send frame {}: {}", msg.id, error))
+    }
+
+    //  Keeps the connection open while there's no frame to send, for servers with an idle timeout
+    pub fn heartbeat(&mut self) -> Result<(), String> {
+        self.stream.write_all(b"{\"heartbeat\":true}\n")
+            .map_err(|error| format!("Could not send a heartbeat: {}", error))
+    }
+
+    //  Blocks until the next event, ends once the server closes the connection
+    pub fn events<'a>(&'a mut self) -> Events<'a> {
+        Events {
+            publisher: self,
+        }
+    }
+
+    //  Calls back for every event received so far without blocking, to be used in a main loop
+    pub fn poll_events<F: FnMut(Event)>(&mut self, mut callback: F) {
+        while let Some(event) = self.pending.pop_front() {
+            callback(event);
+        }
+        for event in self.events.try_iter() {
+            callback(event);
+        }
+    }
+
+    //  In lockstep mode the viewer asks for every next frame, so a bot sends a frame
+    //  and then waits here before computing the next one. Other events are kept for
+    //  events() and poll_events(), errors mean the frame never reached the viewer.
+    pub fn wait_for_step(&mut self) -> Result<MessageOut, String> {
+        loop {
+            match self.events.recv() {
+                Ok(Ok(msg)) => if msg.kind == EventKind::NextFrame {
+                    return Ok(msg);
+                } else {
+                    self.pending.push_back(Ok(msg));
+                },
+                Ok(Err(error)) => return Err(error.error),
+                Err(_)         => return Err(String::from("Server closed the connection")),
+            }
+        }
+    }
+}
+
+pub struct Events<'a> {
+    publisher: &'a mut Publisher,
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        match self.publisher.pending.pop_front() {
+            Some(event) => Some(event),
+            None        => self.publisher.events.recv().ok(),
+        }
+    }
+}
+
+impl Drop for Publisher {
+    //  Wakes up the reader thread
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+//  Returns None once the connection is closed
+fn read_reply<R: BufRead>(reader: &mut R) -> Result<Option<Reply>, String> {
+    let mut line = String::new();
+
+    loop {
+        match reader.read_line(&mut line) {
+            Ok(0)      => return Ok(None),
+            Ok(_)      => if !line.trim().is_empty() { break; },
+            Err(error) => return Err(format!("Connection lost: {}", error)),
+        }
+    }
+
+    let json = Json::from_str(&line).map_err(|error| format!("Invalid reply: {}", error))?;
+    Reply::from_json(json).map(Some)
+}
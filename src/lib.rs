@@ -4,9 +4,14 @@ extern crate cgmath;
 extern crate rustc_serialize;
 extern crate image;
 extern crate regex;
+extern crate sha1;
 
 mod server;
 mod visualization;
 mod types;
 
+pub mod client;
+
 pub use server::core::Server as Server;
+pub use server::networking::Address as Address;
+pub use server::limits::Limits as Limits;
@@ -2,24 +2,20 @@ extern crate show_and_tell;
 extern crate rand;
 
 use std::env;
-use show_and_tell::Server;
+use std::process;
+use show_and_tell::{Server, Address, Limits};
 use rand::Rng;
-use std::net;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::str::FromStr;
-use std::error::Error;
+use std::path::PathBuf;
+use std::time::Duration;
 
-const MIN_VALID_PORT: u32 = 1024;
-const MAX_VALID_PORT: u32 = 49151;
+const MIN_VALID_PORT: u16 = 1024;
+const MAX_VALID_PORT: u16 = 49151;
+const UNIX_SOCKET_PREFIX: &'static str = "unix:";
 
-fn parse_ip(ip_string: &String) -> Result<net::Ipv4Addr, String> {
-    match net::Ipv4Addr::from_str(ip_string) {
-        Ok(address) => Ok(address),
-        Err(error)  => Err(String::from(error.description())),
-    }
-}
-
-fn parse_port(port_string: &String) -> Result<u32, String> {
-    let port = match port_string.parse::<u32>() {
+fn parse_port(port_string: &str) -> Result<u16, String> {
+    let port = match port_string.parse::<u16>() {
         Ok(num) => num,
         Err(_)  => return Err("Invalid port: argument is not a number".to_string()),
     };
@@ -32,32 +28,153 @@ fn parse_port(port_string: &String) -> Result<u32, String> {
     return Ok(port);
 }
 
+//  IP addresses are taken as they are, host names are bound on every address they resolve to
+fn resolve_host(host: &str, port: u16) -> Result<Vec<Address>, String> {
+    match (host, port).to_socket_addrs() {
+        Ok(addresses) => Ok(addresses.map(Address::Tcp).collect()),
+        Err(error)    => Err(format!("Could not resolve {}: {}", host, error)),
+    }
+}
+
+//  Parses a comma separated list of hosts, "host:port" pairs and unix sockets;
+//  the port is shared by all the hosts without their own one
+fn parse_addresses(list: &str, port: Option<u16>) -> Result<Vec<Address>, String> {
+    let default_port = match port {
+        Some(port) => port,
+        None       => rand::thread_rng().gen_range(MIN_VALID_PORT, MAX_VALID_PORT + 1),
+    };
+
+    let mut addresses: Vec<Address> = vec![];
+    for entry in list.split(',').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+        if entry.starts_with(UNIX_SOCKET_PREFIX) {
+            addresses.push(Address::Unix(PathBuf::from(&entry[UNIX_SOCKET_PREFIX.len()..])));
+        } else if let Ok(address) = SocketAddr::from_str(entry) {
+            addresses.push(Address::Tcp(address));
+        } else if entry.matches(':').count() == 1 {
+            let mut parts = entry.splitn(2, ':');
+            let host = parts.next().unwrap_or("");
+            let port = parse_port(parts.next().unwrap_or(""))?;
+            addresses.extend(resolve_host(host, port)?);
+        } else {
+            addresses.extend(resolve_host(entry, default_port)?);
+        }
+    }
+
+    let any_tcp = addresses.iter().any(|address| match *address {
+        Address::Tcp(_)  => true,
+        Address::Unix(_) => false,
+    });
+
+    if addresses.is_empty() {
+        return Err(String::from("No address to listen on"));
+    } else if port.is_some() && !any_tcp {
+        return Err(String::from("Port can't be used with a unix socket"));
+    }
+
+    Ok(addresses)
+}
+
+struct Options {
+    arguments: Vec<String>,
+    tokens_file: Option<PathBuf>,
+    limits: Limits,
+    stale_after: Option<Duration>,
+}
+
+//  A limit of 0 would refuse everything, so it isn't accepted
+fn parse_limit<T: FromStr + PartialOrd + Default>(option: &str, value: Option<String>) -> Result<T, String> {
+    match value.map(|value| value.parse::<T>()) {
+        Some(Ok(limit)) if limit > T::default() => Ok(limit),
+        _                                       => Err(format!("Option {} requires a positive number", option)),
+    }
+}
+
+fn parse_options(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        arguments: vec![],
+        tokens_file: None,
+        limits: Limits::new(),
+        stale_after: None,
+    };
+
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--tokens" => match args.next() {
+                Some(path) => options.tokens_file = Some(PathBuf::from(path)),
+                None       => return Err(String::from("Option --tokens requires a file")),
+            },
+            "--max-frame-size" => options.limits.max_frame_size = parse_limit(&arg, args.next())?,
+            "--max-buffered"   => options.limits.max_buffered = parse_limit(&arg, args.next())?,
+            "--max-rate"       => options.limits.max_messages_per_sec = parse_limit(&arg, args.next())?,
+            "--idle-timeout"   => options.limits.idle_timeout = Some(Duration::from_secs(parse_limit(&arg, args.next())?)),
+            "--stale-after"    => options.stale_after = Some(Duration::from_secs(parse_limit(&arg, args.next())?)),
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _                          => options.arguments.push(arg),
+        }
+    }
+
+    Ok(options)
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 && args.len() != 2 {
-        println!("usage: show_and_tell address [port]");
+    let options = match parse_options(env::args().collect()) {
+        Ok(options) => options,
+        Err(why)    => {
+            println!("{}", why);
+            return;
+        }
+    };
+
+    let args = &options.arguments;
+    if args.len() != 2 && args.len() != 1 {
+        println!("usage: show_and_tell [options] address[,address...] [port]");
+        println!("address is an IPv4 or IPv6 address, a host name, host:port or {}socket_path", UNIX_SOCKET_PREFIX);
+        println!("options:");
+        println!("    --tokens file             publisher tokens, a \"publisher token\" pair per line");
+        println!("    --max-frame-size bytes    largest accepted frame");
+        println!("    --max-buffered bytes      most data a connection may have waiting for a complete frame");
+        println!("    --max-rate messages       most messages a connection may send per second");
+        println!("    --idle-timeout seconds    close connections silent for this long, heartbeats keep them open");
+        println!("    --stale-after seconds     report publishers without a frame for this long, 5 by default");
         return;
     }
 
-    let address = match parse_ip(&args[1]) {
-        Ok(addr) => addr,
-        Err(why) => {
+    let port = match args.get(1).map(|port| parse_port(port)) {
+        Some(Ok(port)) => Some(port),
+        Some(Err(why)) => {
+            println!("{}", why);
+            return;
+        },
+        None           => None,
+    };
+
+    let addresses = match parse_addresses(&args[0], port) {
+        Ok(addresses) => addresses,
+        Err(why)      => {
             println!("{}", why);
             return;
         }
     };
 
-    let mut port: u32 = rand::thread_rng().gen_range(MIN_VALID_PORT, MAX_VALID_PORT + 1);
-    if args.len() == 3 {
-        match parse_port(&args[2]) {
-            Ok(num) => port = num,
-            Err(why) => {
+    let mut server = Server::new(addresses);
+    server.set_limits(options.limits);
+    if let Some(stale_after) = options.stale_after {
+        server.set_stale_after(stale_after);
+    }
+
+    if let Some(ref path) = options.tokens_file {
+        match server.load_tokens(path) {
+            Ok(count) => println!("Loaded tokens for {} publishers", count),
+            Err(why)  => {
                 println!("{}", why);
                 return;
             }
         }
     }
 
-    let mut server = Server::new(address, port);
-    server.run();
+    if let Err(why) = server.run() {
+        println!("{}", why);
+        process::exit(1);
+    }
 }
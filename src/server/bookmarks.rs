@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use rustc_serialize::json;
+
+pub const BOOKMARKS_VERSION: u32 = 1;
+//  Saved next to a recording under its name with this suffix
+pub const BOOKMARKS_SUFFIX: &'static str = ".bookmarks";
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct Bookmark {
+    pub publisher: String,
+    pub frame: String,
+    pub note: String,
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+struct BookmarksFile {
+    version: u32,
+    bookmarks: Vec<Bookmark>,
+}
+
+//  Notes on frames, identified by their publisher and the id of the frame
+pub struct Bookmarks {
+    entries: Vec<Bookmark>,
+}
+
+impl Bookmarks {
+    pub fn new() -> Bookmarks {
+        Bookmarks {
+            entries: vec![],
+        }
+    }
+
+    //  Replaces the note of a frame bookmarked before
+    pub fn add(&mut self, bookmark: Bookmark) {
+        match self.entries.iter().position(|entry| entry.publisher == bookmark.publisher && entry.frame == bookmark.frame) {
+            Some(index) => self.entries[index] = bookmark,
+            None        => self.entries.push(bookmark),
+        }
+    }
+
+    pub fn remove(&mut self, publisher: &str, frame: &str) -> bool {
+        let count = self.entries.len();
+        self.entries.retain(|entry| entry.publisher != publisher || entry.frame != frame);
+        self.entries.len() != count
+    }
+
+    pub fn entries(&self) -> &Vec<Bookmark> {
+        &self.entries
+    }
+
+    //  Notes by frame id
+    pub fn of_publisher(&self, publisher: &str) -> HashMap<String, String> {
+        self.entries.iter()
+            .filter(|entry| entry.publisher == publisher)
+            .map(|entry| (entry.frame.clone(), entry.note.clone()))
+            .collect()
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let contents = BookmarksFile {
+            version:   BOOKMARKS_VERSION,
+            bookmarks: self.entries.clone(),
+        };
+        let text = format!("{}\n", json::as_pretty_json(&contents));
+
+        File::create(path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .map_err(|error| format!("Failed to write {}: {}", path, error))
+    }
+
+    //  Adds the bookmarks of the file to the current ones, returns how many were read
+    pub fn load(&mut self, path: &str) -> Result<usize, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|error| format!("Failed to read {}: {}", path, error))?;
+
+        let contents: BookmarksFile = json::decode(&text)
+            .map_err(|error| format!("{} isn't a bookmarks file ({})", path, error))?;
+        if contents.version > BOOKMARKS_VERSION {
+            return Err(format!("{} was saved in an unsupported version", path));
+        }
+
+        let count = contents.bookmarks.len();
+        for bookmark in contents.bookmarks {
+            self.add(bookmark);
+        }
+        Ok(count)
+    }
+}
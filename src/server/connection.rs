@@ -0,0 +1,550 @@
+use types::message::{MessageIn, MessageError, Reply, Incoming, Hello, Welcome, Encoding, PROTOCOL_VERSION};
+use types::message;
+use server::parser::{MessageParser, Framing};
+use server::websocket;
+use server::websocket::WebSocket;
+use server::tokens::Tokens;
+use server::limits::Limits;
+
+use std::cmp;
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const MIN_PROTOCOL_VERSION: u32 = 1;
+const ENCODINGS: &'static [&'static str] = &["json", "msgpack"];
+const FEATURES: &'static [&'static str] = &["errors", "typed-values", "delta", "permanent-removal", "websocket", "tokens", "subscribe", "heartbeat", "input-events"];
+
+//  What a connection passes on to the listener
+pub enum Request {
+    Publish(MessageIn),
+    Subscribe(String),
+    Unsubscribe(String),
+}
+
+enum Transport {
+    Raw,
+    WebSocket(WebSocket),
+}
+
+//  Protocol state of a single client, independent of the underlying stream
+pub struct Connection {
+    transport: Option<Transport>,
+    sniffed: Vec<u8>,
+    parser: MessageParser,
+    handshake_allowed: bool,
+    tokens: Arc<Tokens>,
+    token: Option<String>,
+    //  Clicks, releases, scrolling and lockstep steps, in the shape with an event kind
+    input_events: bool,
+    limits: Limits,
+    rate_window_start: Instant,
+    rate_window_messages: u32,
+    output: Vec<u8>,
+    closed: bool,
+}
+
+impl Connection {
+    pub fn new(tokens: Arc<Tokens>, limits: Limits) -> Connection {
+        Connection {
+            transport: None,
+            sniffed: vec![],
+            parser: MessageParser::new(limits.max_frame_size),
+            handshake_allowed: true,
+            tokens: tokens,
+            token: None,
+            input_events: false,
+            limits: limits,
+            rate_window_start: Instant::now(),
+            rate_window_messages: 0,
+            output: vec![],
+            closed: false,
+        }
+    }
+
+    //  Returns the requests that should be passed on, replies are queued in the output
+    pub fn receive(&mut self, data: &[u8]) -> Vec<Request> {
+        if self.transport.is_none() {
+            self.sniffed.extend(data);
+
+            self.transport = match websocket::detect(&self.sniffed) {
+                Some(true)  => Some(Transport::WebSocket(WebSocket::new(self.limits.max_frame_size))),
+                Some(false) => Some(Transport::Raw),
+                None        => return vec![],
+            };
+
+            let sniffed = mem::replace(&mut self.sniffed, vec![]);
+            return self.receive(&sniffed);
+        }
+
+        match self.transport {
+            Some(Transport::WebSocket(ref mut socket)) => {
+                if let Err(error) = socket.push(data) {
+                    println!("(Connection) {}", error);
+                }
+
+                while let Some(message) = socket.pop() {
+                    self.parser.push_message(&message);
+                }
+
+                self.output.extend(socket.take_output());
+                self.closed = socket.is_closed();
+            },
+            _ => self.parser.push(data),
+        }
+
+        let mut requests: Vec<Request> = vec![];
+        while let Some(result) = self.parser.pop() {
+            if !self.within_rate() {
+                let error = format!("Exceeded the limit of {} messages per second", self.limits.max_messages_per_sec);
+                self.abort(error, None);
+                return requests;
+            }
+
+            let reply = match result {
+                Ok(Incoming::Frame(msg)) => {
+                    if self.tokens.check(&msg.publisher, self.token.as_ref()) {
+                        requests.push(Request::Publish(msg));
+                        None
+                    } else {
+                        Some(Reply::Error(MessageError {
+                            error:     format!("Publisher {} requires a valid token in the hello", msg.publisher),
+                            publisher: Some(msg.publisher),
+                            id:        Some(msg.id),
+                            offset:    None,
+                        }))
+                    }
+                },
+                Ok(Incoming::Subscribe(publisher)) => {
+                    //  Input events of a protected publisher are only visible to its token holders
+                    if self.tokens.check(&publisher, self.token.as_ref()) {
+                        requests.push(Request::Subscribe(publisher));
+                        None
+                    } else {
+                        Some(Reply::Error(MessageError {
+                            error:     format!("Publisher {} requires a valid token in the hello", publisher),
+                            publisher: Some(publisher),
+                            id:        None,
+                            offset:    None,
+                        }))
+                    }
+                },
+                Ok(Incoming::Unsubscribe(publisher)) => {
+                    requests.push(Request::Unsubscribe(publisher));
+                    None
+                },
+                Ok(Incoming::Heartbeat) => None,
+                Ok(Incoming::Hello(hello)) => {
+                    if self.handshake_allowed {
+                        match self.greet(hello) {
+                            Ok((welcome, encoding)) => {
+                                //  The welcome is still encoded as JSON, the negotiated
+                                //  encoding applies to everything after it
+                                self.send(&Reply::Welcome(welcome));
+                                self.parser.set_encoding(encoding);
+                                None
+                            },
+                            Err(error) => Some(Reply::Error(error)),
+                        }
+                    } else {
+                        Some(Reply::Error(MessageError {
+                            error:     String::from("Hello has to be the first message"),
+                            publisher: hello.publisher,
+                            id:        None,
+                            offset:    None,
+                        }))
+                    }
+                },
+                Err(ref error) if error.fatal => {
+                    println!("(Parser) {}", error);
+                    self.abort(error.reason.clone(), Some(error.offset));
+                    return requests;
+                },
+                Err(error) => {
+                    println!("(Parser) {}", error);
+
+                    Some(Reply::Error(MessageError {
+                        error:     error.reason,
+                        publisher: None,
+                        id:        error.id,
+                        offset:    Some(error.offset),
+                    }))
+                },
+            };
+            self.handshake_allowed = false;
+
+            if let Some(reply) = reply {
+                self.send(&reply);
+            }
+        }
+
+        let buffered = self.parser.buffered() + match self.transport {
+            Some(Transport::WebSocket(ref socket)) => socket.buffered(),
+            _                                      => 0,
+        };
+        if buffered > self.limits.max_buffered {
+            let error = format!("{} bytes are waiting for a complete frame, the limit is {}", buffered, self.limits.max_buffered);
+            self.abort(error, None);
+        }
+
+        requests
+    }
+
+    //  Messages are counted in one second windows
+    fn within_rate(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.rate_window_start) >= Duration::from_secs(1) {
+            self.rate_window_start = now;
+            self.rate_window_messages = 0;
+        }
+
+        self.rate_window_messages += 1;
+        self.rate_window_messages <= self.limits.max_messages_per_sec
+    }
+
+    //  Sends the error and closes the connection, nothing is read after that
+    fn abort(&mut self, error: String, offset: Option<usize>) {
+        println!("(Connection) Closing, {}", error);
+
+        self.send(&Reply::Error(MessageError {
+            error:     error.clone(),
+            publisher: None,
+            id:        None,
+            offset:    offset,
+        }));
+
+        if let Some(Transport::WebSocket(ref mut socket)) = self.transport {
+            socket.close(websocket::STATUS_POLICY_VIOLATION, &error);
+            self.output.extend(socket.take_output());
+        }
+        self.closed = true;
+    }
+
+    //  Called when nothing arrived within the idle timeout
+    pub fn time_out(&mut self) {
+        let seconds = self.limits.idle_timeout.map(|timeout| timeout.as_secs()).unwrap_or(0);
+        self.abort(format!("No data received for {} seconds", seconds), None);
+    }
+
+    pub fn send(&mut self, reply: &Reply) {
+        let encoding = self.parser.encoding();
+        let payload = match *reply {
+            Reply::Event(ref msg) if !self.input_events => match msg.to_legacy() {
+                Some(legacy) => message::serialize(&legacy, encoding),
+                None         => return,
+            },
+            _ => reply.serialize(encoding),
+        };
+
+        let frame = match (&self.transport, encoding) {
+            (&Some(Transport::WebSocket(_)), Encoding::Json)        => websocket::encode_text(&payload),
+            (&Some(Transport::WebSocket(_)), Encoding::MessagePack) => websocket::encode_binary(&payload),
+            _                                                       => self.parser.framing().encode(&payload),
+        };
+        self.output.extend(frame);
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.output, vec![])
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    //  Without a hello, the connection keeps the defaults: JSON encoding
+    //  and the publisher given in every frame
+    fn greet(&mut self, hello: Hello) -> Result<(Welcome, Encoding), MessageError> {
+        let refuse = |error: String, publisher: Option<String>| MessageError {
+            error:     error,
+            publisher: publisher,
+            id:        None,
+            offset:    None,
+        };
+
+        if hello.version < MIN_PROTOCOL_VERSION {
+            let error = format!("Unsupported protocol version {}, use {}-{}",
+                                hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+            return Err(refuse(error, hello.publisher));
+        }
+
+        let encoding_name = hello.encoding.unwrap_or(String::from("json"));
+        let encoding = match Encoding::from_name(&encoding_name) {
+            Some(encoding) if ENCODINGS.contains(&encoding_name.as_str()) => encoding,
+            _ => return Err(refuse(format!("Unsupported encoding {}", encoding_name), hello.publisher)),
+        };
+
+        //  Binary frames can't be told apart by braces or newlines
+        let delimited = match self.transport {
+            Some(Transport::WebSocket(_)) => true,
+            _                             => self.parser.framing() == Framing::LengthPrefixed,
+        };
+        if encoding == Encoding::MessagePack && !delimited {
+            let error = String::from("msgpack encoding requires length-prefixed framing or WebSocket");
+            return Err(refuse(error, hello.publisher));
+        }
+
+        if let Some(ref publisher) = hello.publisher {
+            if !self.tokens.check(publisher, hello.token.as_ref()) {
+                return Err(refuse(format!("Invalid token for publisher {}", publisher), hello.publisher.clone()));
+            }
+        }
+
+        //  Input events change the shape of the events, so they have to be asked for by name
+        let features: Vec<String> = match hello.features {
+            Some(requested) => requested.into_iter()
+                .filter(|feature| FEATURES.contains(&feature.as_str()))
+                .collect(),
+            None            => FEATURES.iter()
+                .filter(|feature| **feature != "input-events")
+                .map(|feature| String::from(*feature))
+                .collect(),
+        };
+
+        self.parser.set_publisher(hello.publisher);
+        self.token = hello.token;
+        self.input_events = features.iter().any(|feature| feature == "input-events");
+
+        let welcome = Welcome {
+            version:  cmp::min(hello.version, PROTOCOL_VERSION),
+            encoding: encoding_name,
+            features: features,
+        };
+
+        Ok((welcome, encoding))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::message::{MessageOut, EventKind, Modifiers};
+    use rustc_serialize::json::Json;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::thread;
+
+    const FRAME: &'static [u8] = br#"{"publisher":"p","id":1,"objects":[]}"#;
+
+    fn connection() -> Connection {
+        Connection::new(Arc::new(Tokens::new()), Limits::new())
+    }
+
+    fn limited(limits: Limits) -> Connection {
+        Connection::new(Arc::new(Tokens::new()), limits)
+    }
+
+    //  Publisher "p" requires the token "secret"
+    fn protected() -> Connection {
+        let path = env::temp_dir().join(format!("tokens-{}-{:?}", process::id(), thread::current().id()));
+        fs::write(&path, "p secret\n").unwrap();
+        let tokens = Tokens::load(&path);
+        let _ = fs::remove_file(&path);
+
+        Connection::new(Arc::new(tokens.unwrap()), Limits::new())
+    }
+
+    fn published(requests: &[Request]) -> Vec<&str> {
+        requests.iter()
+            .filter_map(|request| match *request {
+                Request::Publish(ref msg) => Some(msg.id.as_str()),
+                _                         => None,
+            })
+            .collect()
+    }
+
+    fn error(reply: &Json) -> &str {
+        reply.find("error").and_then(|error| error.as_string()).unwrap()
+    }
+
+    //  Every reply takes a line without the length-prefixed framing
+    fn replies(connection: &mut Connection) -> Vec<Json> {
+        String::from_utf8(connection.take_output()).unwrap()
+            .lines()
+            .map(|line| Json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn event(kind: EventKind, object_id: Option<u32>, key_code: Option<&str>) -> Reply {
+        Reply::Event(MessageOut {
+            publisher: String::from("p"),
+            id:        String::from("1"),
+            kind:      kind,
+            object_id: object_id,
+            key_code:  key_code.map(String::from),
+            button:    None,
+            x:         None,
+            y:         None,
+            scroll:    None,
+            modifiers: Modifiers::default(),
+        })
+    }
+
+    fn welcome_features(reply: &Json) -> Vec<String> {
+        reply.find_path(&["welcome", "features"]).unwrap().as_array().unwrap().iter()
+            .map(|feature| String::from(feature.as_string().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn input_events_only_when_asked_for() {
+        let mut connection = connection();
+        connection.receive(br#"{"hello":{"version":1,"publisher":"p"}}"#);
+        let features = welcome_features(&replies(&mut connection)[0]);
+        assert!(features.contains(&String::from("errors")));
+        assert!(!features.contains(&String::from("input-events")));
+
+        connection.send(&event(EventKind::MouseClick, Some(1), None));
+        connection.send(&event(EventKind::KeyPressed, None, Some("A")));
+        assert!(replies(&mut connection).is_empty());
+
+        connection.send(&event(EventKind::KeyPressed, Some(1), Some("A")));
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].find("kind").is_none());
+        assert_eq!(replies[0].find("object_id"), Some(&Json::U64(1)));
+    }
+
+    #[test]
+    fn input_events_requested() {
+        let mut connection = connection();
+        connection.receive(br#"{"hello":{"version":1,"publisher":"p","features":["input-events","unknown"]}}"#);
+        assert_eq!(welcome_features(&replies(&mut connection)[0]), vec![String::from("input-events")]);
+
+        connection.send(&event(EventKind::MouseClick, None, None));
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].find("kind"), Some(&Json::String(String::from("mouse_click"))));
+        assert_eq!(replies[0].find("object_id"), Some(&Json::Null));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut connection = limited(Limits {
+            max_messages_per_sec: 3,
+            ..Limits::new()
+        });
+
+        let requests = connection.receive(&FRAME.repeat(5));
+        assert_eq!(requests.len(), 3);
+        assert!(connection.is_closed());
+
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(error(&replies[0]), "Exceeded the limit of 3 messages per second");
+    }
+
+    #[test]
+    fn rate_limit_window() {
+        let mut connection = limited(Limits {
+            max_messages_per_sec: 3,
+            ..Limits::new()
+        });
+
+        assert_eq!(connection.receive(&FRAME.repeat(3)).len(), 3);
+        connection.rate_window_start -= Duration::from_secs(1);
+        assert_eq!(connection.receive(&FRAME.repeat(3)).len(), 3);
+        assert!(!connection.is_closed());
+        assert!(replies(&mut connection).is_empty());
+    }
+
+    #[test]
+    fn buffered_limit() {
+        let mut connection = limited(Limits {
+            max_buffered: 64,
+            ..Limits::new()
+        });
+
+        connection.receive(br#"{"publisher":"p","id":1,"objects":["#);
+        assert!(!connection.is_closed());
+
+        connection.receive(&[b' '; 40]);
+        assert!(connection.is_closed());
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 1);
+        assert!(error(&replies[0]).ends_with("the limit is 64"));
+    }
+
+    #[test]
+    fn oversize_frame_aborts() {
+        let mut connection = limited(Limits {
+            max_frame_size: 16,
+            ..Limits::new()
+        });
+
+        let requests = connection.receive(FRAME);
+        assert!(requests.is_empty());
+        assert!(connection.is_closed());
+
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 1);
+        assert!(error(&replies[0]).contains("exceeds the limit of 16 bytes"));
+        assert!(replies[0].find("offset").unwrap().is_u64());
+    }
+
+    #[test]
+    fn oversize_websocket_frame_aborts() {
+        let mut connection = limited(Limits {
+            max_frame_size: 16,
+            ..Limits::new()
+        });
+
+        connection.receive(b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n");
+        assert!(connection.take_output().starts_with(b"HTTP/1.1 101"));
+
+        connection.receive(&[0x81, 0xFE, 0x01, 0x00]);
+        assert!(connection.is_closed());
+        assert_eq!(connection.take_output()[0], 0x88);
+    }
+
+    #[test]
+    fn frames_of_protected_publishers_need_the_token() {
+        let mut connection = protected();
+        let requests = connection.receive(br#"{"publisher":"p","id":1,"objects":[]}{"publisher":"q","id":2,"objects":[]}"#);
+        assert_eq!(published(&requests), vec!["2"]);
+
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(error(&replies[0]), "Publisher p requires a valid token in the hello");
+        assert_eq!(replies[0].find("id"), Some(&Json::String(String::from("1"))));
+        assert!(!connection.is_closed());
+    }
+
+    #[test]
+    fn hello_with_the_token() {
+        let mut connection = protected();
+        let requests = connection.receive(br#"{"hello":{"version":1,"publisher":"p","token":"secret"}}{"id":1,"objects":[]}{"subscribe":"p"}"#);
+        assert_eq!(published(&requests), vec!["1"]);
+        assert_eq!(requests.len(), 2);
+
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].find("welcome").is_some());
+    }
+
+    #[test]
+    fn hello_with_a_wrong_token() {
+        let mut connection = protected();
+        let requests = connection.receive(br#"{"hello":{"version":1,"publisher":"p","token":"guess"}}{"id":1,"objects":[]}{"subscribe":"p"}"#);
+        assert!(requests.is_empty());
+
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 3);
+        assert_eq!(error(&replies[0]), "Invalid token for publisher p");
+        assert_eq!(error(&replies[1]), "missing field \"publisher\"");
+        assert_eq!(error(&replies[2]), "Publisher p requires a valid token in the hello");
+    }
+
+    #[test]
+    fn hello_has_to_be_first() {
+        let mut connection = protected();
+        let requests = connection.receive(br#"{"publisher":"q","id":1,"objects":[]}{"hello":{"version":1,"publisher":"p","token":"secret"}}{"publisher":"p","id":2,"objects":[]}"#);
+        assert_eq!(published(&requests), vec!["1"]);
+
+        let replies = replies(&mut connection);
+        assert_eq!(replies.len(), 2);
+        assert_eq!(error(&replies[0]), "Hello has to be the first message");
+        assert_eq!(error(&replies[1]), "Publisher p requires a valid token in the hello");
+    }
+}
@@ -1,53 +1,50 @@
 use std::io;
 use std::thread;
-use std::time::Duration;
+use std::sync::mpsc::{Sender, Receiver};
 
-use types::double_channel::{channel, Endpoint};
+use server::core::Event;
 
 pub struct Console {
-    link_core: Endpoint<String, String>,
+    link_core: Sender<Event>,
+    responses: Receiver<String>,
 }
 
 impl Console {
-    pub fn new(link: Endpoint<String, String>) -> Console {
+    pub fn new(link: Sender<Event>, responses: Receiver<String>) -> Console {
         Console {
             link_core: link,
+            responses: responses,
         }
     }
 
-    fn non_blocking_stdin(link: Endpoint<String, ()>) {
+    fn read_stdin(link: Sender<Event>) {
         let mut line = String::new();
 
         loop {
-            if let Ok(_) = io::stdin().read_line(&mut line) {
-                let trimmed = String::from(line.trim());
-                let _ = link.send(trimmed);
-                line.clear();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_)          => {
+                    let trimmed = String::from(line.trim());
+                    if link.send(Event::Command(trimmed)).is_err() {
+                        break;
+                    }
+                    line.clear();
+                },
             }
-
-            thread::sleep(Duration::from_millis(10));
         }
     }
 
     pub fn run(&self) {
-        let (ch_input, ch_me_input) = channel::<String, ()>();
+        let link = self.link_core.clone();
 
         thread::spawn(move || {
-            Console::non_blocking_stdin(ch_input);
+            Console::read_stdin(link);
         });
 
-        loop {
-            if let Ok(command) = ch_me_input.try_recv() {
-                let _ = self.link_core.send(command);
+        for response in self.responses.iter() {
+            for (i, line) in response.split("\n").enumerate() {
+                println!("{} {}", if i == 0 { ">>>" } else { "..." } , line.trim());
             }
-
-            if let Ok(response) = self.link_core.try_recv() {
-                for (i, line) in response.split("\n").enumerate() {
-                    println!("{} {}", if i == 0 { ">>>" } else { "..." } , line.trim());
-                }
-            }
-
-            thread::sleep(Duration::from_millis(10));
         }
     }
 }
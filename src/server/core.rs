@@ -1,98 +1,252 @@
-use types::message::{MessageIn, MessageOut};
-use types::double_channel::{channel, Endpoint};
-use server::networking::Listener;
+use types::message::{MessageIn, MessageError, Reply};
+use types::double_channel::channel;
+use server::networking::{Listener, Address};
+use server::networking::Event as ListenerEvent;
 use server::console::Console;
-use visualization::core::Visualization;
+use server::tokens::Tokens;
+use server::limits::Limits;
+use server::recording;
+use server::recording::{Recorder, Direction};
+use server::bookmarks::{Bookmarks, Bookmark, BOOKMARKS_SUFFIX};
+use visualization::core::{Visualization, FramePolicy, Command, Report};
+use visualization::breakpoint::Breakpoint;
 
+use std::fs;
 use std::thread;
-use std::collections::HashMap;
-use std::net;
-use std::time::{Instant, Duration};
-use std::fs::File;
-use std::io::Write;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+
+const DEFAULT_STALE_AFTER_SECS: u64 = 5;
+const SILENCE_CHECK_INTERVAL_MS: u64 = 500;
+
+//  Everything the core reacts to arrives through a single channel
+pub enum Event {
+    Frame(MessageIn),
+    Command(String),
+    //  Sent on behalf of the visualization with the given id
+    Visualization(usize, Report),
+    //  A connection started sending frames of the publisher, the connection name comes second
+    PublisherConnected(String, String),
+    PublisherDisconnected(String),
+    //  Sent once for every address, with the outcome of binding it
+    Listening(Address),
+    BindFailed,
+}
+
+struct VisualizationLink {
+    id:        usize,
+    link:      Sender<Command>,
+    //  Of the shown frames, differs from the name of the visualization for replays
+    publisher: String,
+    //  Replays are fed from a recording, their input events have nowhere to go
+    replay:    bool,
+}
 
 pub struct Server {
-    port: u32,
-    address: net::Ipv4Addr,
-    visualizations: HashMap<String, Endpoint<Option<MessageIn>, Option<MessageOut>>>,
-    traffic_log_file: Option<(File, String)>,
+    addresses: Vec<Address>,
+    //  Addresses bound so far, and how many are yet to report
+    listening: Vec<Address>,
+    binds_pending: usize,
+    visualizations: HashMap<String, VisualizationLink>,
+    visualizations_count: usize,
+    recorder: Option<Recorder>,
+    //  The last recording written or replayed, bookmarks are saved beside it
+    last_recording: Option<String>,
+    bookmarks: Bookmarks,
+    //  Numbered in the order of adding, with the name of their visualization
+    breakpoints: Vec<(usize, String, Breakpoint)>,
+    breakpoints_count: usize,
+    unknown_publishers: HashSet<String>,
+    tokens: Arc<Tokens>,
+    limits: Limits,
+    stale_after: Duration,
+    last_frames: HashMap<String, Instant>,
+    silent_publishers: HashSet<String>,
+    seen_publishers: HashSet<String>,
+    events: Sender<Event>,
+    inbox: Receiver<Event>,
 }
 
 impl Server {
-    pub fn new(address: net::Ipv4Addr, port: u32) -> Server {
+    pub fn new(addresses: Vec<Address>) -> Server {
+        let (events, inbox) = mpsc::channel::<Event>();
+
         Server {
-            port: port,
-            address: address,
-            visualizations: HashMap::<String, Endpoint<Option<MessageIn>, Option<MessageOut>>>::new(),
-            traffic_log_file: None,
+            binds_pending: addresses.len(),
+            listening: vec![],
+            addresses: addresses,
+            visualizations: HashMap::<String, VisualizationLink>::new(),
+            visualizations_count: 0,
+            recorder: None,
+            last_recording: None,
+            bookmarks: Bookmarks::new(),
+            breakpoints: vec![],
+            breakpoints_count: 0,
+            unknown_publishers: HashSet::<String>::new(),
+            tokens: Arc::new(Tokens::new()),
+            limits: Limits::new(),
+            stale_after: Duration::from_secs(DEFAULT_STALE_AFTER_SECS),
+            last_frames: HashMap::<String, Instant>::new(),
+            silent_publishers: HashSet::<String>::new(),
+            seen_publishers: HashSet::<String>::new(),
+            events: events,
+            inbox: inbox,
         }
     }
 
-    pub fn run(&mut self) {
-        let (ch_listener, ch_me_listener) = channel::<MessageIn, MessageOut>();
-        let (ch_console, ch_me_console) = channel::<String, String>();
+    //  Returns the number of publishers protected by a token
+    pub fn load_tokens(&mut self, path: &Path) -> Result<usize, String> {
+        let tokens = Tokens::load(path)?;
+        let count = tokens.len();
+        self.tokens = Arc::new(tokens);
+        Ok(count)
+    }
 
-        {
-            let port = self.port;
-            let address = self.address;
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
 
-            thread::spawn(move || {
-                let listener = Listener::new(address, port, ch_listener);
-                listener.run();
-            });
+    //  Publishers without a frame for this long are reported as silent and their windows as stale
+    pub fn set_stale_after(&mut self, stale_after: Duration) {
+        self.stale_after = stale_after;
+    }
 
-            thread::spawn(move || {
-                let console = Console::new(ch_console);
-                console.run();
-            });
-        }
+    //  Fails if none of the addresses could be bound
+    pub fn run(&mut self) -> Result<(), String> {
+        let listener = Listener::new(self.addresses.clone(), self.tokens.clone(), self.limits, self.events.clone());
+        let link_listener = listener.sender();
+
+        let (ch_console, ch_me_console) = mpsc::channel::<String>();
+        let console = Console::new(self.events.clone(), ch_me_console);
+
+        thread::spawn(move || {
+            listener.run();
+        });
+
+        thread::spawn(move || {
+            console.run();
+        });
 
         loop {
-            let time_now = Instant::now();
-
-            if let Ok(msg) = ch_me_listener.try_recv() {
-                if let Some(link) = self.visualizations.get(&msg.publisher) {
-                    let log = format!("{:?}\n", msg);
-                    match self.traffic_log_file {
-                        None => {},
-                        Some((ref mut file, _)) => { let _ = file.write(log.as_bytes()); },
+            self.report_silent_publishers(&ch_console);
+
+            let event = match self.inbox.recv_timeout(Duration::from_millis(SILENCE_CHECK_INTERVAL_MS)) {
+                Ok(event)                           => event,
+                Err(RecvTimeoutError::Timeout)      => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            match event {
+                Event::Frame(msg) => {
+                    self.last_frames.insert(msg.publisher.clone(), Instant::now());
+                    if self.silent_publishers.remove(&msg.publisher) {
+                        let _ = ch_console.send(format!("Publisher {} is sending frames again", msg.publisher));
                     }
-                    let _ = link.send(Some(msg));
-                }
-            }
 
-            if let Ok(command) = ch_me_console.try_recv() {
-                let (response, quit) = self.execute_command(command);
-                let _ = ch_me_console.send(response);
+                    if let Some(visualization) = self.visualizations.get(&msg.publisher) {
+                        if let Some(ref mut recorder) = self.recorder {
+                            recorder.frame(&msg);
+                        }
+                        let _ = visualization.link.send(Command::Frame(msg));
+                    } else if self.unknown_publishers.insert(msg.publisher.clone()) {
+                        //  Reported once per connection, until a visualization for the publisher is started
+                        let _ = link_listener.send(ListenerEvent::Reply(Reply::Error(MessageError {
+                            error:     format!("No visualization is running for publisher {}", msg.publisher),
+                            publisher: Some(msg.publisher),
+                            id:        Some(msg.id),
+                            offset:    None,
+                        })));
+                    }
+                },
+                Event::Command(command) => {
+                    let (response, quit) = self.execute_command(command);
+                    let _ = ch_console.send(response);
+
+                    if quit {
+                        thread::sleep(Duration::from_millis(50));
+                        break;
+                    }
+                },
+                Event::Visualization(id, Report::Event(msg)) => {
+                    if self.visualizations.values().any(|visualization| visualization.id == id && visualization.replay) {
+                        continue;
+                    }
 
-                if quit {
-                    thread::sleep(Duration::from_millis(50));
-                    break;
-                }
+                    if let Some(ref mut recorder) = self.recorder {
+                        recorder.event(&msg);
+                    }
+                    let _ = link_listener.send(ListenerEvent::Reply(Reply::Event(msg)));
+                },
+                Event::Visualization(_, Report::BreakpointHit(number, hit)) => {
+                    let condition = self.breakpoints.iter()
+                        .find(|&&(n, _, _)| n == number)
+                        .map(|&(_, ref name, ref breakpoint)| format!("in {}: {}", name, breakpoint.text()))
+                        .unwrap_or(String::new());
+                    let object = hit.object.map(|id| format!(", object {}", id)).unwrap_or(String::new());
+
+                    let _ = ch_console.send(format!("Breakpoint {} {} hit at frame {}{}", number, condition, hit.frame, object));
+                },
+                Event::Visualization(id, Report::Closed) => {
+                    //  A replaced visualization may report its closing after the new one started
+                    let name = self.visualizations.iter()
+                        .find(|&(_, visualization)| visualization.id == id)
+                        .map(|(name, _)| name.clone());
+
+                    if let Some(name) = name {
+                        let _ = ch_console.send(format!("Visualization {} has been stopped", name));
+                        self.stop_visualization(name);
+                    }
+                },
+                Event::PublisherConnected(publisher, connection) => {
+                    if !self.seen_publishers.insert(publisher.clone()) {
+                        let _ = ch_console.send(format!("Publisher {} reconnected from {}", publisher, connection));
+                    }
+                },
+                Event::Listening(address) => {
+                    self.binds_pending -= 1;
+                    self.listening.push(address);
+                },
+                Event::BindFailed => {
+                    self.binds_pending -= 1;
+                    if self.binds_pending == 0 && self.listening.is_empty() {
+                        return Err(String::from("Could not listen on any of the addresses"));
+                    }
+                },
+                Event::PublisherDisconnected(publisher) => {
+                    //  Silence of a closed connection isn't worth another notice
+                    self.last_frames.remove(&publisher);
+                    self.silent_publishers.remove(&publisher);
+                    //  A reconnected publisher is told again that nothing shows its frames
+                    self.unknown_publishers.remove(&publisher);
+                    let _ = ch_console.send(format!("Publisher {} disconnected", publisher));
+                },
             }
+        }
+
+        self.remove_unix_sockets();
+        Ok(())
+    }
 
-            let mut removed_visualizations: Vec<String> = vec![];
-            for (name, link) in &self.visualizations {
-                if let Ok(response) = link.try_recv() {
-                    match response {
-                        Some(msg) => {
-                            let log = format!("{:?}\n", msg);
-                            match self.traffic_log_file {
-                                None => {},
-                                Some((ref mut file, _)) => { let _ = file.write(log.as_bytes()); },
-                            }
-                            let _ = ch_me_listener.send(msg);
-                        },
-                        None      => { removed_visualizations.push(name.clone()); },
-                    };
+    //  Only the sockets bound by this server, others at the same paths may still be in use
+    fn remove_unix_sockets(&self) {
+        for address in &self.listening {
+            if let Address::Unix(ref path) = *address {
+                if let Err(error) = fs::remove_file(path) {
+                    println!("(Server) Could not remove {}: {}", address, error);
                 }
             }
-            for name in removed_visualizations {
-                let _ = ch_me_console.send(format!("Visualization {} has been stopped", name));
-                self.stop_visualization(name);
-            }
+        }
+    }
 
-            thread::sleep(Duration::from_millis(10));
+    fn report_silent_publishers(&mut self, console: &Sender<String>) {
+        let now = Instant::now();
+        for (publisher, last_frame) in &self.last_frames {
+            if now.duration_since(*last_frame) >= self.stale_after && self.silent_publishers.insert(publisher.clone()) {
+                let _ = console.send(format!("Publisher {} went silent, no frame for {} seconds", publisher, self.stale_after.as_secs()));
+            }
         }
     }
 
@@ -105,8 +259,19 @@ impl Server {
 
         match (words[0], words.len()) {
             ("list", 1)   => (self.print_visualizations(), false),
-            ("start", 3)  => (self.start_visualization(words[1].to_string(), words[2].to_string()), false),
+            ("start", 3)  => (self.start_visualization(words[1].to_string(), words[2].to_string(), FramePolicy::Queue), false),
+            ("start", 4)  => match FramePolicy::from_name(words[3]) {
+                Some(policy) => (self.start_visualization(words[1].to_string(), words[2].to_string(), policy), false),
+                None         => (format!("Unknown frame policy \"{}\", use latest, queue or lockstep", words[3]), false),
+            },
+            ("replay", 3) => (self.replay(words[1], words[2].to_string(), 1.0), false),
+            ("replay", 4) => match words[3].parse::<f64>() {
+                Ok(speed) if speed > 0.0 => (self.replay(words[1], words[2].to_string(), speed), false),
+                _                        => (format!("Invalid speed \"{}\", use a positive number", words[3]), false),
+            },
             ("close", 2)  => (self.stop_visualization(words[1].to_string()), false),
+            ("bookmark", n) if n >= 2   => (self.execute_bookmark_command(&words[1..]), false),
+            ("breakpoint", n) if n >= 2 => (self.execute_breakpoint_command(&words[1..]), false),
             ("log", 2) |
             ("log", 3)    => (self.launch_or_stop_traffic_log(words), false),
             ("quit", 1) |
@@ -126,65 +291,296 @@ impl Server {
         response
     }
 
-    fn start_visualization(&mut self, publisher: String, configuration: String) -> String {
-        let (ch_window, ch_me_window) = channel::<Option<MessageOut>, Option<MessageIn>>();
+    fn start_visualization(&mut self, publisher: String, configuration: String, policy: FramePolicy) -> String {
+        let (ch_window, ch_me_window) = channel::<Report, Command>();
 
         let p = publisher.clone();
+        let stale_after = self.stale_after;
         thread::spawn(move || {
-            let mut visualization = Visualization::new(ch_window, p, configuration);
+            let mut visualization = Visualization::new(ch_window, p, configuration, policy, stale_after);
             visualization.run();
         });
 
-        let status = self.visualizations.insert(publisher, ch_me_window);
+        self.visualizations_count += 1;
+        let id = self.visualizations_count;
+
+        let (link, responses) = ch_me_window.split();
+        let events = self.events.clone();
+        thread::spawn(move || {
+            for response in responses.iter() {
+                if events.send(Event::Visualization(id, response)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let _ = link.send(Command::Bookmarks(self.bookmarks.of_publisher(&publisher)));
+        let _ = link.send(Command::Breakpoints(self.breakpoints_of(&publisher)));
+
+        self.unknown_publishers.remove(&publisher);
+        let status = self.visualizations.insert(publisher.clone(), VisualizationLink {
+            id:        id,
+            link:      link,
+            publisher: publisher,
+            replay:    false,
+        });
 
         let info = match status {
-            Some(link) => {
-                let _ = link.send(None);
+            Some(visualization) => {
+                let _ = visualization.link.send(Command::Close);
                 "Warning: closing previous visualization\n".to_string()
             }
-            None       => {
+            None                => {
                 String::from("")
             }
         };
         format!("{}New visualization started succesfully", info)
     }
 
+    //  Plays the frames of the first publisher in the recording into a new visualization,
+    //  named after the file so that it can be closed like any other
+    fn replay(&mut self, path: &str, configuration: String, speed: f64) -> String {
+        let recording = match recording::load(path) {
+            Ok(recording) => recording,
+            Err(error)    => return error,
+        };
+        let records = recording.records;
+        let truncated_status = if recording.truncated {
+            String::from("\nThe last record was cut off and has been skipped")
+        } else {
+            String::new()
+        };
+
+        let publisher = match records.iter().find(|record| record.direction == Direction::In) {
+            Some(record) => record.publisher.clone(),
+            None         => return format!("{} has no frames to replay", path),
+        };
+
+        let mut frames: Vec<(f64, MessageIn)> = vec![];
+        for record in records {
+            if record.direction != Direction::In || record.publisher != publisher {
+                continue;
+            }
+            match MessageIn::from_json(record.payload, None) {
+                Ok(msg)    => frames.push((record.time, msg)),
+                Err(error) => return format!("{} has an invalid frame: {}", path, error),
+            }
+        }
+
+        self.last_recording = Some(String::from(path));
+        let bookmarks_path = format!("{}{}", path, BOOKMARKS_SUFFIX);
+        let bookmarks_status = if Path::new(&bookmarks_path).exists() {
+            match self.bookmarks.load(&bookmarks_path) {
+                Ok(count)  => format!("\nLoaded {} bookmarks from {}", count, bookmarks_path),
+                Err(error) => format!("\n{}", error),
+            }
+        } else {
+            String::new()
+        };
+
+        let name = format!("replay:{}", path);
+        let count = frames.len();
+        let status = self.start_visualization(name.clone(), configuration, FramePolicy::Queue);
+
+        let link = match self.visualizations.get_mut(&name) {
+            Some(visualization) => {
+                visualization.publisher = publisher.clone();
+                visualization.replay = true;
+                visualization.link.clone()
+            },
+            None                => return status,
+        };
+        let _ = link.send(Command::Bookmarks(self.bookmarks.of_publisher(&publisher)));
+        thread::spawn(move || {
+            feed_replay(frames, speed, link);
+        });
+
+        format!("{}\nReplaying {} frames of publisher {} as {}{}{}", status, count, publisher, name, truncated_status, bookmarks_status)
+    }
+
     fn stop_visualization(&mut self, publisher: String) -> String {
         match self.visualizations.remove(&publisher) {
-            Some(link) => {
-                let _ = link.send(None);
+            Some(visualization) => {
+                let _ = visualization.link.send(Command::Close);
                 format!("Visualization {} stopped succesfully", publisher)
             },
-            None       => format!("Visualization {} isn't currently running", publisher),
+            None                => format!("Visualization {} isn't currently running", publisher),
         }
     }
 
-    fn launch_or_stop_traffic_log(&mut self, args: Vec<&str>) -> String {
-        use std::path::Path;
+    //  Bookmarks refer to the publisher of the frames, which for a replay
+    //  is the one in the recording rather than the name of the visualization
+    fn execute_bookmark_command(&mut self, args: &[&str]) -> String {
+        match (args[0], args.len()) {
+            ("list", 1) => {
+                let mut response = String::from("Bookmarks:");
+                for bookmark in self.bookmarks.entries() {
+                    response.push_str(&format!("\n{} {}: {}", bookmark.publisher, bookmark.frame, bookmark.note));
+                }
+                response
+            },
+            ("add", n) if n >= 3 => {
+                let publisher = self.frames_publisher(args[1]);
+                self.bookmarks.add(Bookmark {
+                    publisher: publisher.clone(),
+                    frame:     args[2].to_string(),
+                    note:      args[3..].join(" "),
+                });
+                self.send_bookmarks();
+                format!("Bookmarked frame {} of {}", args[2], publisher)
+            },
+            ("remove", 3) => {
+                let publisher = self.frames_publisher(args[1]);
+                if self.bookmarks.remove(&publisher, args[2]) {
+                    self.send_bookmarks();
+                    format!("Removed the bookmark of frame {} of {}", args[2], publisher)
+                } else {
+                    format!("Frame {} of {} isn't bookmarked", args[2], publisher)
+                }
+            },
+            ("goto", 3) => match self.visualizations.get(args[1]) {
+                Some(visualization) => {
+                    let _ = visualization.link.send(Command::Goto(args[2].to_string()));
+                    format!("Showing frame {} in {}", args[2], args[1])
+                },
+                None                => format!("Visualization {} isn't currently running", args[1]),
+            },
+            ("save", 1) |
+            ("save", 2) => match self.bookmarks_path(args.get(1)) {
+                Ok(path)   => match self.bookmarks.save(&path) {
+                    Ok(())     => format!("Saved {} bookmarks to {}", self.bookmarks.entries().len(), path),
+                    Err(error) => error,
+                },
+                Err(error) => error,
+            },
+            ("load", 1) |
+            ("load", 2) => match self.bookmarks_path(args.get(1)) {
+                Ok(path)   => match self.bookmarks.load(&path) {
+                    Ok(count)  => {
+                        self.send_bookmarks();
+                        format!("Loaded {} bookmarks from {}", count, path)
+                    },
+                    Err(error) => error,
+                },
+                Err(error) => error,
+            },
+            _ => format!("Invalid command: \"bookmark {}\"", args.join(" ")),
+        }
+    }
+
+    fn frames_publisher(&self, name: &str) -> String {
+        match self.visualizations.get(name) {
+            Some(visualization) => visualization.publisher.clone(),
+            None                => String::from(name),
+        }
+    }
+
+    //  Without a file name, the bookmarks belong beside the last recording
+    fn bookmarks_path(&self, path: Option<&&str>) -> Result<String, String> {
+        match (path, self.last_recording.as_ref()) {
+            (Some(path), _)         => Ok(path.to_string()),
+            (None, Some(recording)) => Ok(format!("{}{}", recording, BOOKMARKS_SUFFIX)),
+            (None, None)            => Err(String::from("No recording to keep the bookmarks beside, give a file name")),
+        }
+    }
+
+    fn send_bookmarks(&self) {
+        for visualization in self.visualizations.values() {
+            let _ = visualization.link.send(Command::Bookmarks(self.bookmarks.of_publisher(&visualization.publisher)));
+        }
+    }
 
+    //  Breakpoints belong to a visualization name, so they survive restarting it
+    fn execute_breakpoint_command(&mut self, args: &[&str]) -> String {
+        match (args[0], args.len()) {
+            ("list", 1) => {
+                let mut response = String::from("Breakpoints:");
+                for &(number, ref name, ref breakpoint) in &self.breakpoints {
+                    response.push_str(&format!("\n{} in {}: {}", number, name, breakpoint.text()));
+                }
+                response
+            },
+            ("add", n) if n >= 3 => match Breakpoint::parse(&args[2..].join(" ")) {
+                Ok(breakpoint) => {
+                    self.breakpoints_count += 1;
+                    self.breakpoints.push((self.breakpoints_count, args[1].to_string(), breakpoint));
+                    self.send_breakpoints(args[1]);
+                    format!("Added breakpoint {} to {}", self.breakpoints_count, args[1])
+                },
+                Err(error)     => error,
+            },
+            ("remove", 2) => {
+                let number = args[1].parse::<usize>().unwrap_or(0);
+                match self.breakpoints.iter().position(|&(n, _, _)| n == number) {
+                    Some(index) => {
+                        let (_, name, _) = self.breakpoints.remove(index);
+                        self.send_breakpoints(&name);
+                        format!("Removed breakpoint {}", number)
+                    },
+                    None        => format!("There's no breakpoint {}", args[1]),
+                }
+            },
+            _ => format!("Invalid command: \"breakpoint {}\"", args.join(" ")),
+        }
+    }
+
+    fn breakpoints_of(&self, name: &str) -> Vec<(usize, Breakpoint)> {
+        self.breakpoints.iter()
+            .filter(|&&(_, ref visualization, _)| visualization == name)
+            .map(|&(number, _, ref breakpoint)| (number, breakpoint.clone()))
+            .collect()
+    }
+
+    fn send_breakpoints(&self, name: &str) {
+        if let Some(visualization) = self.visualizations.get(name) {
+            let _ = visualization.link.send(Command::Breakpoints(self.breakpoints_of(name)));
+        }
+    }
+
+    fn launch_or_stop_traffic_log(&mut self, args: Vec<&str>) -> String {
         match (args[1], args.len()) {
             ("start", 3) => {
                 let filename = args[2];
-                let path = Path::new(&filename);
-                match File::create(path) {
-                    Err(_) => {
-                        format!("Failed to open {}", filename)
-                    },
-                    Ok(handle) => {
-                        self.traffic_log_file = Some((handle, filename.to_string()));
+                match Recorder::create(filename) {
+                    Err(error)   => error,
+                    Ok(recorder) => {
+                        self.recorder = Some(recorder);
+                        self.last_recording = Some(filename.to_string());
                         format!("Saving logs to {}", filename)
                     }
                 }
             }
             ("stop", 2)  => {
-                let status = match self.traffic_log_file {
-                    None                    => format!("No logger running"),
-                    Some((_, ref mut name)) => format!("Logs saved to {}", name),
+                let status = match self.recorder {
+                    None               => format!("No logger running"),
+                    Some(ref recorder) => format!("Logs saved to {}", recorder.path()),
                 };
-                self.traffic_log_file = None;
+                self.recorder = None;
                 status
             },
             _            => format!("Invalid command: \"{}\"", args.join(" "))
         }
     }
 }
+
+//  Sends the frames with their recorded spacing, divided by the speed
+fn feed_replay(frames: Vec<(f64, MessageIn)>, speed: f64, link: Sender<Command>) {
+    let start = Instant::now();
+    let first_time = frames.first().map(|&(time, _)| time).unwrap_or(0.0);
+
+    for (time, msg) in frames {
+        let due = (time - first_time) / speed;
+        let elapsed = start.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + (elapsed.subsec_nanos() as f64) / 1000000000.0;
+
+        if due > elapsed {
+            let wait = due - elapsed;
+            thread::sleep(Duration::new(wait as u64, (wait.fract() * 1000000000.0) as u32));
+        }
+
+        //  Fails once the visualization is closed
+        if link.send(Command::Frame(msg)).is_err() {
+            break;
+        }
+    }
+}
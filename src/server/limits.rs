@@ -0,0 +1,23 @@
+use std::time::Duration;
+
+//  Per-connection limits, a connection exceeding any of them gets an error and is closed
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_frame_size: usize,
+    //  Bytes received but not parsed yet, including the frame in progress
+    pub max_buffered: usize,
+    pub max_messages_per_sec: u32,
+    //  Longest silence allowed, heartbeats keep quiet connections open
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            max_frame_size:       16 * 1024 * 1024,
+            max_buffered:         64 * 1024 * 1024,
+            max_messages_per_sec: 1000,
+            idle_timeout:         None,
+        }
+    }
+}
@@ -1,3 +1,10 @@
 pub mod core;
-mod networking;
+pub mod networking;
+mod connection;
+mod parser;
+mod websocket;
 mod console;
+mod tokens;
+mod recording;
+mod bookmarks;
+pub mod limits;
@@ -1,249 +1,374 @@
-use types::message::{MessageIn, MessageOut};
-use types::double_channel::{channel, Endpoint};
-
-use std::{thread};
-use std::net::{TcpListener, TcpStream, Ipv4Addr};
+use types::message::{MessageError, Reply};
+use server::connection::{Connection, Request};
+use server::core;
+use server::tokens::Tokens;
+use server::limits::Limits;
+
+use std::{thread, fmt, io};
+use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
 use std::io::{Write, Read};
-use std::time::Duration;
-use std::collections::{VecDeque, HashMap};
-use rustc_serialize::json;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+const WRITE_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Clone)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Address::Tcp(ref address) => write!(f, "{}", address),
+            Address::Unix(ref path)   => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+//  Everything the listener reacts to arrives through a single channel
+pub enum Event {
+    Connected(String, Sender<ConnectionEvent>),
+    Request(String, Request),
+    Disconnected(String),
+    Reply(Reply),
+}
+
+//  Everything a connection thread reacts to
+pub enum ConnectionEvent {
+    Data(Vec<u8>),
+    Reply(Reply),
+    Closed,
+}
+
+//  Streams that can be read and written from separate threads
+trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self);
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
 
-type ConnectionLink = Endpoint<MessageOut, Option<MessageIn>>;
-type ConnectionData = (String, ConnectionLink);
+    fn shutdown(&self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+}
 
-const TCP_BUFFER_SIZE: usize = 1000000;
+#[cfg(unix)]
+impl Stream for ::std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        ::std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) {
+        let _ = ::std::os::unix::net::UnixStream::shutdown(self, Shutdown::Both);
+    }
+}
 
 pub struct Listener {
-    port: u32,
-    address: Ipv4Addr,
-    link_core: Endpoint<MessageIn, MessageOut>,
+    addresses: Vec<Address>,
+    tokens: Arc<Tokens>,
+    limits: Limits,
+    link_core: Sender<core::Event>,
+    events: Sender<Event>,
+    inbox: Receiver<Event>,
 }
 
 impl Listener {
-    pub fn new(address: Ipv4Addr, port: u32, link: Endpoint<MessageIn, MessageOut>) -> Listener {
+    pub fn new(addresses: Vec<Address>, tokens: Arc<Tokens>, limits: Limits, link: Sender<core::Event>) -> Listener {
+        let (events, inbox) = mpsc::channel::<Event>();
+
         Listener {
-            port: port,
-            address: address,
+            addresses: addresses,
+            tokens: tokens,
+            limits: limits,
             link_core: link,
+            events: events,
+            inbox: inbox,
         }
     }
 
-    fn handle_connection(mut stream: TcpStream, link: Endpoint<Option<MessageIn>, MessageOut>) {
-        let mut buffer: [u8; TCP_BUFFER_SIZE] = [0; TCP_BUFFER_SIZE];
-        let mut parser = MessageParser::new();
+    pub fn sender(&self) -> Sender<Event> {
+        self.events.clone()
+    }
+
+    fn read_stream<S: Read>(mut stream: S, link: Sender<ConnectionEvent>) {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
 
         loop {
             match stream.read(&mut buffer) {
+                Ok(0)          => break,
                 Ok(bytes_read) => {
-                    let slice = &buffer[0..bytes_read];
-                    parser.push(slice);
-
-                    while let Some(msg) = parser.pop() {
-                        match link.send(Some(msg)) {
-                            Ok(_)  => {},
-                            Err(_) => println!("(Connection) Failed to send a message to main thread"),
-                        };
+                    if link.send(ConnectionEvent::Data(buffer[0..bytes_read].to_vec())).is_err() {
+                        return;
                     }
                 },
-                Err(error) => {
-                    use std::io::ErrorKind::*;
-
-                    match error.kind() {
-                        WouldBlock | TimedOut => {},
-                        _ => {
-                            println!("(Connection) {:?}", error);
-                            break;
-                        },
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => {},
+                Err(error)     => {
+                    println!("(Connection) {:?}", error);
+                    break;
+                },
+            }
+        }
+
+        let _ = link.send(ConnectionEvent::Closed);
+    }
+
+    fn handle_connection<S: Stream>(name: String, mut stream: S, tokens: Arc<Tokens>, limits: Limits,
+                                    inbox: Receiver<ConnectionEvent>, link: Sender<Event>) {
+        let mut connection = Connection::new(tokens, limits);
+        let mut last_data = Instant::now();
+
+        loop {
+            let event = match limits.idle_timeout {
+                Some(timeout) => inbox.recv_timeout(timeout.checked_sub(last_data.elapsed()).unwrap_or(Duration::from_secs(0))),
+                None          => inbox.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match event {
+                Ok(ConnectionEvent::Data(data)) => {
+                    last_data = Instant::now();
+                    for request in connection.receive(&data) {
+                        if link.send(Event::Request(name.clone(), request)).is_err() {
+                            println!("(Connection) Failed to send a message to main thread");
+                        }
                     }
                 },
+                Ok(ConnectionEvent::Reply(reply))   => connection.send(&reply),
+                Ok(ConnectionEvent::Closed)         => break,
+                Err(RecvTimeoutError::Timeout)      => connection.time_out(),
+                Err(RecvTimeoutError::Disconnected) => break,
             }
 
-            if let Ok(response) = link.try_recv() {
-                let json_response = format!("{}\n", json::as_json(&response).to_string());
-                let _ = stream.write(json_response.as_bytes());
+            let output = connection.take_output();
+            if !output.is_empty() && stream.write_all(&output).is_err() {
+                break;
             }
 
-            thread::sleep(Duration::from_millis(10));
+            if connection.is_closed() {
+                break;
+            }
         }
 
-        let _ = link.send(None);
+        //  Wakes up the reader thread if the connection was closed from this side
+        stream.shutdown();
+        let _ = link.send(Event::Disconnected(name));
+    }
+
+    fn spawn_connection<S: Stream>(stream: S, connection_name: String, tokens: &Arc<Tokens>, limits: Limits, link: &Sender<Event>) {
+        let reader = match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(error) => {
+                println!("(Listener) Could not set up connection {}: {:?}", connection_name, error);
+                return;
+            },
+        };
+
+        let (ch_connection, ch_me_connection) = mpsc::channel::<ConnectionEvent>();
+        let _ = link.send(Event::Connected(connection_name.clone(), ch_connection.clone()));
+
+        thread::spawn(move || {
+            Listener::read_stream(reader, ch_connection);
+        });
+
+        let tokens = tokens.clone();
+        let link = link.clone();
+        thread::spawn(move || {
+            Listener::handle_connection(connection_name, stream, tokens, limits, ch_me_connection, link);
+        });
     }
 
-    fn listen_to_clients(address: Ipv4Addr, port: u32, link: Endpoint<ConnectionData, ()>) {
-        let listener = TcpListener::bind(format!("{}:{}", address, port))
-            .expect("Invalid IP address or port");
+    //  The core is told whether binding succeeded, so it can give up when nothing did
+    fn listen_to_clients(address: Address, tokens: Arc<Tokens>, limits: Limits, link: Sender<Event>, link_core: Sender<core::Event>) {
+        match address {
+            Address::Tcp(address) => Listener::listen_to_tcp_clients(address, tokens, limits, link, link_core),
+            Address::Unix(path)   => Listener::listen_to_unix_clients(path, tokens, limits, link, link_core),
+        }
+    }
 
-        let five_milliseconds = Duration::from_millis(20);
+    fn listen_to_tcp_clients(address: SocketAddr, tokens: Arc<Tokens>, limits: Limits, link: Sender<Event>, link_core: Sender<core::Event>) {
+        let listener = match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(error)   => {
+                println!("(Listener) Could not bind {}: {}", address, error);
+                let _ = link_core.send(core::Event::BindFailed);
+                return;
+            },
+        };
+        println!("(Listener) Listening on {}", address);
+        let _ = link_core.send(core::Event::Listening(Address::Tcp(address)));
 
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
                     println!("(Connection) New client, {:?}!", stream);
 
-                    let _ = stream.set_read_timeout(Some(five_milliseconds));
+                    let _ = stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)));
                     let _ = stream.set_nodelay(true);
 
                     let connection_name = match stream.peer_addr() {
-                        Ok(addr) => format!("{:?}:{}", addr.ip(), addr.port()),
+                        Ok(addr) => addr.to_string(),
                         Err(_)   => {
                             println!("(Listener) Could not determine client address");
                             continue;
                         }
                     };
 
-                    let (ch_connection, ch_me_connection) = channel::<Option<MessageIn>, MessageOut>();
-
-                    thread::spawn(move || {
-                        Listener::handle_connection(stream, ch_connection);
-                    });
-
-                    let _ = link.send((connection_name, ch_me_connection));
+                    Listener::spawn_connection(stream, connection_name, &tokens, limits, &link);
                 },
                 Err(_) => {},
             }
         }
     }
 
-    pub fn run(&self) {
-        loop {
-            println!("(Listener) Listening on port {}", self.port);
-
-            let address = self.address;
-            let port = self.port;
-
-            let mut connections = HashMap::<String, ConnectionLink>::new();
-            let mut publishers = HashMap::<String, String>::new();
+    #[cfg(unix)]
+    fn listen_to_unix_clients(path: PathBuf, tokens: Arc<Tokens>, limits: Limits, link: Sender<Event>, link_core: Sender<core::Event>) {
+        use std::fs;
+        use std::os::unix::fs::FileTypeExt;
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        //  A socket file left behind by a previous server would make binding fail, but
+        //  one still accepting connections belongs to a running server and is kept
+        if let Ok(metadata) = fs::metadata(&path) {
+            if metadata.file_type().is_socket() {
+                match UnixStream::connect(&path) {
+                    Err(ref error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                        let _ = fs::remove_file(&path);
+                    },
+                    _ => {},
+                }
+            }
+        }
 
-            let (ch_listener, ch_me_listener) = channel::<ConnectionData, ()>();
+        let listener = match UnixListener::bind(&path) {
+            Ok(listener) => listener,
+            Err(error)   => {
+                println!("(Listener) Could not bind unix:{}: {}", path.display(), error);
+                let _ = link_core.send(core::Event::BindFailed);
+                return;
+            },
+        };
+        println!("(Listener) Listening on unix:{}", path.display());
+        let _ = link_core.send(core::Event::Listening(Address::Unix(path.clone())));
 
-            thread::spawn(move || {
-                Listener::listen_to_clients(address, port, ch_listener);
-            });
+        let mut clients_count: u64 = 0;
 
-            loop {
-                if let Ok((name, link)) = ch_me_listener.try_recv() {
-                    let _ = connections.insert(name, link);
-                }
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    println!("(Connection) New client, {:?}!", stream);
 
-                let mut closed_connections: Vec<String> = vec![];
-                for (name, link) in &connections {
-                    if let Ok(option_msg) = link.try_recv() {
-                        match option_msg {
-                            Some(msg) => {
-                                let _ = publishers.insert(msg.publisher.clone(), name.clone());
-                                let _ = self.link_core.send(msg);
-                            },
-                            None => {
-                                closed_connections.push(name.clone());
-                            }
-                        }
-                    };
-                }
-                for name in closed_connections {
-                    connections.remove(&name);
-                }
+                    let _ = stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS)));
 
-                let mut removed_publishers: Vec<String> = vec![];
-                if let Ok(msg) = self.link_core.try_recv() {
-                    match publishers.get(&msg.publisher) {
-                        Some(name) => if let Some(link) = connections.get(name) {
-                                let _ = link.send(msg);
-                            } else {
-                                removed_publishers.push(String::from(msg.publisher));
-                            },
-                        None => {
-                            println!("(Networking) Publisher {} not found", &msg.publisher);
-                        }
-                    }
-                }
-                for publisher in removed_publishers {
-                    publishers.remove(&publisher);
-                }
+                    //  Clients of a unix socket are usually unnamed
+                    clients_count += 1;
+                    let connection_name = format!("unix:{}#{}", path.display(), clients_count);
 
-                thread::sleep(Duration::from_millis(10));
+                    Listener::spawn_connection(stream, connection_name, &tokens, limits, &link);
+                },
+                Err(_) => {},
             }
         }
     }
-}
-
-struct MessageParser {
-    buffer: Vec<u8>,
-    open_parentheses: u32,
-    parentheses_counter: u32,
-    messages: VecDeque<MessageIn>,
-}
 
-impl MessageParser {
-    fn new() -> MessageParser {
-        MessageParser {
-            buffer: vec![],
-            open_parentheses: 0,
-            parentheses_counter: 0,
-            messages: VecDeque::<MessageIn>::new(),
-        }
+    #[cfg(not(unix))]
+    fn listen_to_unix_clients(path: PathBuf, _tokens: Arc<Tokens>, _limits: Limits, _link: Sender<Event>, link_core: Sender<core::Event>) {
+        println!("(Listener) Unix domain sockets are not supported on this platform, can't bind {}", path.display());
+        let _ = link_core.send(core::Event::BindFailed);
     }
 
-    fn parse(text: &[u8]) -> Option<MessageIn> {
-        let message_utf8 = match String::from_utf8(text.to_vec()) {
-            Ok(utf) => utf,
-            Err(_)  => {
-                println!("(Parser) Message is not utf8-encoded");
-                return None;
-            },
-        };
+    pub fn run(&self) {
+        for address in &self.addresses {
+            let address = address.clone();
+            let tokens = self.tokens.clone();
+            let limits = self.limits;
+            let events = self.events.clone();
+            let link_core = self.link_core.clone();
 
-        let decoded: Result<MessageIn, _> = json::decode(&message_utf8);
-        match decoded {
-            Err(_)  => {
-                println!("(Parser) Invalid JSON object");
-                None
-            },
-            Ok(msg) => {
-                Some(msg)
-            },
+            thread::spawn(move || {
+                Listener::listen_to_clients(address, tokens, limits, events, link_core);
+            });
         }
-    }
 
-    fn push(&mut self, text: &[u8]) {
-        let len_prev = self.buffer.len();
-        self.buffer.extend(text);
+        let mut connections = HashMap::<String, Sender<ConnectionEvent>>::new();
+        let mut publishers = HashMap::<String, String>::new();
+        let mut subscribers = HashMap::<String, HashSet<String>>::new();
 
-        let mut msg_start: usize = 0;
+        for event in self.inbox.iter() {
+            match event {
+                Event::Connected(name, link) => {
+                    let _ = connections.insert(name, link);
+                },
+                Event::Request(name, Request::Publish(msg)) => {
+                    //  A publisher belongs to the first connection using it, until it disconnects
+                    if !publishers.contains_key(&msg.publisher) {
+                        publishers.insert(msg.publisher.clone(), name.clone());
+                        let _ = self.link_core.send(core::Event::PublisherConnected(msg.publisher.clone(), name.clone()));
+                    }
 
-        for i in len_prev..self.buffer.len() {
-            match self.buffer[i] as char {
-                '{' => {
-                    self.open_parentheses += 1;
-                    self.parentheses_counter += 1;
+                    let owner = publishers[&msg.publisher].clone();
+                    if owner == name {
+                        let _ = self.link_core.send(core::Event::Frame(msg));
+                    } else if let Some(link) = connections.get(&name) {
+                        let _ = link.send(ConnectionEvent::Reply(Reply::Error(MessageError {
+                            error:     format!("Publisher {} is already used by another connection", msg.publisher),
+                            publisher: Some(msg.publisher),
+                            id:        Some(msg.id),
+                            offset:    None,
+                        })));
+                    }
                 },
-                '}' => {
-                    if self.open_parentheses != 0 {
-                        self.open_parentheses -= 1;
+                Event::Request(name, Request::Subscribe(publisher)) => {
+                    subscribers.entry(publisher).or_insert(HashSet::<String>::new()).insert(name);
+                },
+                Event::Request(name, Request::Unsubscribe(publisher)) => {
+                    if let Some(names) = subscribers.get_mut(&publisher) {
+                        names.remove(&name);
                     }
                 },
-                _   => {},
-            };
+                Event::Disconnected(name) => {
+                    connections.remove(&name);
+                    for (publisher, owner) in &publishers {
+                        if *owner == name {
+                            let _ = self.link_core.send(core::Event::PublisherDisconnected(publisher.clone()));
+                        }
+                    }
+                    publishers.retain(|_, owner| *owner != name);
+                    for names in subscribers.values_mut() {
+                        names.remove(&name);
+                    }
+                    subscribers.retain(|_, names| !names.is_empty());
+                },
+                Event::Reply(reply) => {
+                    if let Some(publisher) = reply.publisher().cloned() {
+                        let mut recipients = HashSet::<&String>::new();
+                        recipients.extend(publishers.get(&publisher));
+
+                        //  Errors concern the frames of the owner, subscribers get only the events
+                        if let Reply::Event(_) = reply {
+                            recipients.extend(subscribers.get(&publisher).into_iter().flat_map(|names| names.iter()));
+                        }
 
-            if self.open_parentheses == 0 {
-                let slice = &self.buffer[msg_start..(i+1)];
-                msg_start = i + 1;
+                        if recipients.is_empty() {
+                            println!("(Networking) Publisher {} not found", publisher);
+                        }
 
-                if self.parentheses_counter > 0 {
-                    if let Some(msg) = MessageParser::parse(slice) {
-                        self.messages.push_back(msg);
+                        for name in recipients {
+                            if let Some(link) = connections.get(name) {
+                                let _ = link.send(ConnectionEvent::Reply(reply.clone()));
+                            }
+                        }
                     }
-                }
-
-                self.parentheses_counter = 0;
+                },
             }
         }
-
-        if msg_start != 0 {
-            self.buffer = self.buffer.split_off(msg_start);
-        }
-    }
-
-    fn pop(&mut self) -> Option<MessageIn> {
-        self.messages.pop_front()
     }
 }
@@ -0,0 +1,709 @@
+use types::message::{Incoming, Encoding, value_to_string};
+use types::msgpack;
+
+use std::fmt;
+use std::str;
+use std::collections::VecDeque;
+use rustc_serialize::json;
+use rustc_serialize::json::Json;
+use rustc_serialize::json::ParserError::SyntaxError;
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub offset: usize,
+    pub reason: String,
+    pub id: Option<String>,
+    //  The parser can't continue after this error
+    pub fatal: bool,
+}
+
+impl ParseError {
+    fn new(offset: usize, reason: String) -> ParseError {
+        ParseError {
+            offset: offset,
+            reason: reason,
+            id:     None,
+            fatal:  false,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid frame at byte {}: {}", self.offset, self.reason)
+    }
+}
+
+const MAX_PREAMBLE_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    //  Frames are top-level JSON objects, delimited by their matching braces
+    Stream,
+    //  Every frame is terminated by a newline
+    Lines,
+    //  Every frame is preceded by its length as a big-endian 32-bit integer
+    LengthPrefixed,
+}
+
+impl Framing {
+    fn from_name(name: &str) -> Option<Framing> {
+        match name {
+            "json"   => Some(Framing::Stream),
+            "ndjson" => Some(Framing::Lines),
+            "length" => Some(Framing::LengthPrefixed),
+            _        => None,
+        }
+    }
+
+    pub fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        match *self {
+            Framing::Stream | Framing::Lines => {
+                let mut frame = payload.to_vec();
+                frame.push(b'\n');
+                frame
+            },
+            Framing::LengthPrefixed => {
+                let length = payload.len() as u32;
+                let mut frame = vec![
+                    (length >> 24) as u8,
+                    (length >> 16) as u8,
+                    (length >> 8) as u8,
+                    length as u8,
+                ];
+                frame.extend(payload);
+                frame
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ScanState {
+    //  Between two frames, only whitespace is allowed
+    Idle,
+    //  Inside of a frame that started at the given buffer index
+    Frame(usize),
+    //  After an error, everything up to the next newline is dropped
+    Resync,
+}
+
+//  A delimited frame, decoded only once it's popped
+struct RawFrame {
+    bytes: Vec<u8>,
+    offset: usize,
+}
+
+pub struct MessageParser {
+    framing: Option<Framing>,
+    encoding: Encoding,
+    buffer: Vec<u8>,
+    buffer_offset: usize,
+    consumed: usize,
+    scanned: usize,
+    state: ScanState,
+    nesting: Vec<u8>,
+    in_string: bool,
+    escaped: bool,
+    frames: VecDeque<Result<RawFrame, ParseError>>,
+    publisher: Option<String>,
+    max_frame_size: usize,
+    failed: bool,
+}
+
+impl MessageParser {
+    pub fn new(max_frame_size: usize) -> MessageParser {
+        MessageParser {
+            framing: None,
+            encoding: Encoding::Json,
+            buffer: vec![],
+            buffer_offset: 0,
+            consumed: 0,
+            scanned: 0,
+            state: ScanState::Idle,
+            nesting: vec![],
+            in_string: false,
+            escaped: false,
+            frames: VecDeque::<Result<RawFrame, ParseError>>::new(),
+            publisher: None,
+            max_frame_size: max_frame_size,
+            failed: false,
+        }
+    }
+
+    fn parse(&self, text: &[u8], offset: usize) -> Result<Incoming, ParseError> {
+        let decoded = match self.encoding {
+            Encoding::Json        => self.decode_json(text, offset)?,
+            Encoding::MessagePack => match msgpack::decode(text) {
+                Ok(value)  => value,
+                Err(error) => return Err(ParseError::new(offset + error.offset, error.reason)),
+            },
+        };
+
+        let id = decoded.find("id").map(value_to_string);
+        Incoming::from_json(decoded, self.publisher.as_ref()).map_err(|reason| {
+            let mut error = ParseError::new(offset, reason);
+            error.id = id;
+            error
+        })
+    }
+
+    fn decode_json(&self, text: &[u8], offset: usize) -> Result<Json, ParseError> {
+        let message_utf8 = match str::from_utf8(text) {
+            Ok(utf) => utf,
+            Err(error) => {
+                let reason = String::from("message is not utf8-encoded");
+                return Err(ParseError::new(offset + error.valid_up_to(), reason));
+            },
+        };
+
+        let decoded = match Json::from_str(message_utf8) {
+            Ok(json)   => json,
+            Err(SyntaxError(code, line, column)) => {
+                let position = position_to_offset(message_utf8, line, column);
+                let reason = format!("invalid JSON object ({})", json::error_str(code));
+                return Err(ParseError::new(offset + position, reason));
+            },
+            Err(error) => return Err(ParseError::new(offset, format!("{}", error))),
+        };
+
+        Ok(decoded)
+    }
+
+    fn fail(&mut self, index: usize, reason: String) {
+        let offset = self.buffer_offset + index;
+        self.frames.push_back(Err(ParseError::new(offset, reason)));
+
+        self.nesting.clear();
+        self.in_string = false;
+        self.escaped = false;
+        self.state = if self.buffer[index] == b'\n' { ScanState::Idle } else { ScanState::Resync };
+    }
+
+    fn fail_too_large(&mut self, index: usize, size: usize) {
+        let offset = self.buffer_offset + index;
+        let reason = format!("frame of {} bytes exceeds the limit of {} bytes", size, self.max_frame_size);
+        let mut error = ParseError::new(offset, reason);
+        error.fatal = true;
+
+        self.frames.push_back(Err(error));
+        self.failed = true;
+    }
+
+    //  Frames without a "publisher" field are attributed to this one, starting
+    //  with the next popped frame, even if it arrived together with the hello
+    pub fn set_publisher(&mut self, publisher: Option<String>) {
+        self.publisher = publisher;
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing.unwrap_or(Framing::Stream)
+    }
+
+    //  Applies from the next popped frame on, even if it arrived together with the hello
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    //  A connection may start with a "FRAMING <mode>" line, otherwise
+    //  the frames are expected to be a stream of JSON objects
+    fn negotiate(&mut self) {
+        let first = match self.buffer.iter().position(|&byte| !is_whitespace(byte)) {
+            Some(index) => index,
+            None        => return,
+        };
+
+        if self.buffer[first] == b'{' {
+            self.framing = Some(Framing::Stream);
+            return;
+        }
+
+        let line_length = match self.buffer[first..].iter().position(|&byte| byte == b'\n') {
+            Some(length) => length,
+            None         => {
+                if self.buffer.len() - first > MAX_PREAMBLE_LENGTH {
+                    self.framing = Some(Framing::Stream);
+                    self.fail(first, String::from("invalid framing preamble"));
+                }
+                return;
+            },
+        };
+
+        let preamble = String::from_utf8_lossy(&self.buffer[first..(first + line_length)]).into_owned();
+        let words: Vec<&str> = preamble.split_whitespace().collect();
+
+        let framing = match (words.get(0), words.get(1), words.len()) {
+            (Some(&"FRAMING"), Some(mode), 2) => Framing::from_name(mode),
+            _                                 => None,
+        };
+
+        self.consumed = first + line_length + 1;
+        self.scanned = self.consumed;
+
+        match framing {
+            Some(framing) => self.framing = Some(framing),
+            None          => {
+                self.framing = Some(Framing::Stream);
+                let reason = format!("invalid framing preamble \"{}\"", preamble.trim());
+                self.frames.push_back(Err(ParseError::new(self.buffer_offset + first, reason)));
+            },
+        }
+    }
+
+    fn split_stream(&mut self) {
+        for i in self.scanned..self.buffer.len() {
+            let byte = self.buffer[i];
+
+            match self.state {
+                ScanState::Resync => {
+                    if byte == b'\n' {
+                        self.state = ScanState::Idle;
+                    }
+                },
+                ScanState::Idle => {
+                    match byte {
+                        b'{' => {
+                            self.nesting.push(b'}');
+                            self.state = ScanState::Frame(i);
+                        },
+                        _ if is_whitespace(byte) => {},
+                        _ => {
+                            let reason = format!("unexpected byte 0x{:02x} outside of a frame", byte);
+                            self.fail(i, reason);
+                        },
+                    }
+                },
+                ScanState::Frame(start) => {
+                    if self.in_string {
+                        if self.escaped {
+                            self.escaped = false;
+                        } else if byte == b'\\' {
+                            self.escaped = true;
+                        } else if byte == b'"' {
+                            self.in_string = false;
+                        } else if byte < 0x20 {
+                            self.fail(i, String::from("unterminated string"));
+                        }
+                        continue;
+                    }
+
+                    match byte {
+                        b'"' => self.in_string = true,
+                        b'{' => self.nesting.push(b'}'),
+                        b'[' => self.nesting.push(b']'),
+                        b'}' | b']' => {
+                            if self.nesting.pop() != Some(byte) {
+                                let reason = format!("unexpected '{}'", byte as char);
+                                self.fail(i, reason);
+                                continue;
+                            }
+
+                            if self.nesting.is_empty() {
+                                //  The limit is checked at the end of the push for incomplete frames
+                                let size = i + 1 - start;
+                                if size > self.max_frame_size {
+                                    self.fail_too_large(start, size);
+                                    return;
+                                }
+
+                                let offset = self.buffer_offset + start;
+                                self.queue(start, i + 1, offset);
+                                self.state = ScanState::Idle;
+                            }
+                        },
+                        _ => {},
+                    }
+                },
+            }
+        }
+
+        //  Everything before the current frame can't be a part of any future frame
+        self.consumed = match self.state {
+            ScanState::Frame(start) => start,
+            _                       => self.buffer.len(),
+        };
+        self.scanned = self.buffer.len();
+
+        if let ScanState::Frame(start) = self.state {
+            let size = self.buffer.len() - start;
+            if size > self.max_frame_size {
+                self.fail_too_large(start, size);
+            }
+        }
+    }
+
+    fn split_lines(&mut self) {
+        let mut search_from = self.scanned;
+
+        while let Some(length) = self.buffer[search_from..].iter().position(|&byte| byte == b'\n') {
+            let start = self.consumed;
+            let end = search_from + length;
+            self.consumed = end + 1;
+            search_from = end + 1;
+
+            if end - start > self.max_frame_size {
+                self.fail_too_large(start, end - start);
+                return;
+            }
+
+            if self.buffer[start..end].iter().all(|&byte| is_whitespace(byte)) {
+                continue;
+            }
+
+            let offset = self.buffer_offset + start;
+            self.queue(start, end, offset);
+        }
+
+        self.scanned = self.buffer.len();
+
+        let size = self.buffer.len() - self.consumed;
+        if size > self.max_frame_size {
+            let start = self.consumed;
+            self.fail_too_large(start, size);
+        }
+    }
+
+    fn split_length_prefixed(&mut self) {
+        const HEADER_LENGTH: usize = 4;
+
+        while self.buffer.len() - self.consumed >= HEADER_LENGTH {
+            let header = &self.buffer[self.consumed..(self.consumed + HEADER_LENGTH)];
+            let length = ((header[0] as usize) << 24) | ((header[1] as usize) << 16)
+                | ((header[2] as usize) << 8) | (header[3] as usize);
+
+            let start = self.consumed + HEADER_LENGTH;
+            if length > self.max_frame_size {
+                let header_start = self.consumed;
+                self.fail_too_large(header_start, length);
+                break;
+            }
+
+            //  The buffer grows as the bytes arrive, the header alone doesn't allocate anything
+            if self.buffer.len() - start < length {
+                break;
+            }
+
+            let offset = self.buffer_offset + start;
+            self.queue(start, start + length, offset);
+            self.consumed = start + length;
+        }
+
+        self.scanned = self.buffer.len();
+    }
+
+    fn queue(&mut self, start: usize, end: usize, offset: usize) {
+        let frame = RawFrame {
+            bytes:  self.buffer[start..end].to_vec(),
+            offset: offset,
+        };
+        self.frames.push_back(Ok(frame));
+    }
+
+    pub fn push(&mut self, text: &[u8]) {
+        if self.failed {
+            return;
+        }
+
+        self.buffer.extend(text);
+
+        if self.framing.is_none() {
+            self.negotiate();
+        }
+
+        match self.framing {
+            None                          => return,
+            Some(Framing::Stream)         => self.split_stream(),
+            Some(Framing::Lines)          => self.split_lines(),
+            Some(Framing::LengthPrefixed) => self.split_length_prefixed(),
+        }
+
+        //  Nothing is read after a fatal error, the splitting may have stopped halfway
+        if self.failed {
+            return;
+        }
+
+        //  Drop everything that has already been consumed
+        let consumed = self.consumed;
+        if consumed != 0 {
+            self.buffer = self.buffer.split_off(consumed);
+            self.buffer_offset += consumed;
+            self.scanned -= consumed;
+            self.consumed = 0;
+            if let ScanState::Frame(start) = self.state {
+                self.state = ScanState::Frame(start - consumed);
+            }
+        }
+    }
+
+    //  Parses a frame that has already been delimited by the transport
+    pub fn push_message(&mut self, message: &[u8]) {
+        if self.failed {
+            return;
+        }
+
+        if message.len() > self.max_frame_size {
+            self.fail_too_large(0, message.len());
+            return;
+        }
+
+        self.frames.push_back(Ok(RawFrame {
+            bytes:  message.to_vec(),
+            offset: self.buffer_offset,
+        }));
+        self.buffer_offset += message.len();
+    }
+
+    //  Bytes received but not split into frames yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    //  Decodes with the publisher and the encoding set at the time of popping,
+    //  so a hello applies to the frames sent right after it
+    pub fn pop(&mut self) -> Option<Result<Incoming, ParseError>> {
+        self.frames.pop_front().map(|frame| frame.and_then(|frame| self.parse(&frame.bytes, frame.offset)))
+    }
+}
+
+fn is_whitespace(byte: u8) -> bool {
+    match byte {
+        b' ' | b'\t' | b'\r' | b'\n' => true,
+        _                            => false,
+    }
+}
+
+//  Converts a line/column pair reported by rustc_serialize into a byte offset.
+//  Columns start at 1, but past the first line the newline itself is column 1.
+fn position_to_offset(text: &str, line: usize, column: usize) -> usize {
+    let (line_start, column) = if line > 1 {
+        match text.match_indices('\n').nth(line - 2) {
+            Some((index, _)) => (index + 1, column.saturating_sub(1)),
+            None             => (0, column),
+        }
+    } else {
+        (0, column)
+    };
+
+    text[line_start..].char_indices()
+        .nth(column.saturating_sub(1))
+        .map(|(index, _)| line_start + index)
+        .unwrap_or(text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+    fn pop_all(parser: &mut MessageParser) -> Vec<Result<Incoming, ParseError>> {
+        let mut results = vec![];
+        while let Some(result) = parser.pop() {
+            results.push(result);
+        }
+        results
+    }
+
+    fn frame_ids(parser: &mut MessageParser) -> Vec<String> {
+        pop_all(parser).into_iter()
+            .map(|result| match result {
+                Ok(Incoming::Frame(msg)) => msg.id,
+                other                    => panic!("expected a frame, got {:?}", other),
+            })
+            .collect()
+    }
+
+    fn length_prefixed(payload: &[u8]) -> Vec<u8> {
+        Framing::LengthPrefixed.encode(payload)
+    }
+
+    #[test]
+    fn stream_frames_split_across_reads() {
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        let data = br#"{"publisher":"p","id":1,"objects":[{"name":"{ \"}"}]} {"publisher":"p","id":2,"objects":[]}"#;
+
+        for chunk in data.chunks(7) {
+            parser.push(chunk);
+        }
+
+        assert_eq!(frame_ids(&mut parser), vec!["1", "2"]);
+        assert_eq!(parser.buffered(), 0);
+    }
+
+    #[test]
+    fn stream_resyncs_after_an_error() {
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        parser.push(b"{\"publisher\":\"p\",\"id\":1,\"objects\":[]]\n{\"publisher\":\"p\",\"id\":2,\"objects\":[]}");
+
+        let results = pop_all(&mut parser);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    fn lines_framing_after_preamble() {
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        parser.push(b"FRAMING ndjson\n{\"publisher\":\"p\",\"id\":1,");
+        parser.push(b"\"objects\":[]}\n\n{\"publisher\":\"p\",\"id\":2,\"objects\":[]}\n");
+
+        assert_eq!(parser.framing(), Framing::Lines);
+        assert_eq!(frame_ids(&mut parser), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn invalid_preamble() {
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        parser.push(b"FRAMING xml\n{\"publisher\":\"p\",\"id\":1,\"objects\":[]}");
+
+        let results = pop_all(&mut parser);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    fn length_prefixed_frames_split_across_reads() {
+        let mut data = b"FRAMING length\n".to_vec();
+        data.extend(length_prefixed(br#"{"publisher":"p","id":1,"objects":[]}"#));
+        data.extend(length_prefixed(br#"{"publisher":"p","id":2,"objects":[]}"#));
+
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        for byte in &data {
+            parser.push(&[*byte]);
+        }
+
+        assert_eq!(frame_ids(&mut parser), vec!["1", "2"]);
+        assert_eq!(parser.buffered(), 0);
+    }
+
+    #[test]
+    fn length_prefix_over_the_limit_is_fatal() {
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        parser.push(b"FRAMING length\n\xff\xff\xff\xff{}");
+
+        let results = pop_all(&mut parser);
+        assert_eq!(results.len(), 1);
+        match results[0] {
+            Err(ref error) => assert!(error.fatal),
+            Ok(_)          => panic!("expected an error"),
+        }
+
+        //  Nothing is read after a fatal error
+        parser.push(&length_prefixed(br#"{"publisher":"p","id":1,"objects":[]}"#));
+        assert!(parser.pop().is_none());
+    }
+
+    #[test]
+    fn length_prefix_doesnt_allocate_before_the_payload() {
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        parser.push(b"FRAMING length\n\x00\x0f\x00\x00");
+
+        assert!(parser.pop().is_none());
+        assert!(parser.buffer.capacity() < 1024);
+    }
+
+    #[test]
+    fn stream_frame_over_the_limit_is_fatal() {
+        let mut parser = MessageParser::new(16);
+        parser.push(br#"{"publisher":"p","id":1,"#);
+
+        match parser.pop() {
+            Some(Err(ref error)) => assert!(error.fatal),
+            other                => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn whole_frames_over_the_limit_are_fatal() {
+        let frame = br#"{"publisher":"p","id":1,"objects":[]}"#;
+
+        let mut parser = MessageParser::new(16);
+        parser.push(frame);
+        match parser.pop() {
+            Some(Err(ref error)) => assert!(error.fatal),
+            other                => panic!("expected an error, got {:?}", other),
+        }
+        assert!(parser.pop().is_none());
+
+        let mut parser = MessageParser::new(16);
+        let mut data = b"FRAMING ndjson\n".to_vec();
+        data.extend(frame);
+        data.extend(b"\n{}\n");
+        parser.push(&data);
+        match parser.pop() {
+            Some(Err(ref error)) => assert_eq!(error.offset, 15),
+            other                => panic!("expected an error, got {:?}", other),
+        }
+        assert!(parser.pop().is_none());
+    }
+
+    #[test]
+    fn error_offsets_on_later_lines() {
+        let text = "{\n  \"publisher\": \"p\",\n  \"id\": ?\n}";
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        parser.push(b"  ");
+        parser.push(text.as_bytes());
+
+        match parser.pop() {
+            Some(Err(error)) => assert_eq!(error.offset, 2 + text.find('?').unwrap()),
+            other            => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn publisher_of_the_hello_applies_to_pipelined_frames() {
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        parser.push(br#"{"hello":{"version":1,"publisher":"bot"}}{"id":1,"objects":[]}"#);
+
+        match parser.pop() {
+            Some(Ok(Incoming::Hello(hello))) => parser.set_publisher(hello.publisher),
+            other                            => panic!("expected a hello, got {:?}", other),
+        }
+        match parser.pop() {
+            Some(Ok(Incoming::Frame(msg))) => assert_eq!(msg.publisher, "bot"),
+            other                          => panic!("expected a frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn encoding_of_the_hello_applies_to_pipelined_frames() {
+        let mut frame = BTreeMap::<String, Json>::new();
+        frame.insert(String::from("publisher"), Json::String(String::from("p")));
+        frame.insert(String::from("id"), Json::U64(1));
+        frame.insert(String::from("objects"), Json::Array(vec![]));
+
+        let mut data = b"FRAMING length\n".to_vec();
+        data.extend(length_prefixed(br#"{"hello":{"version":1,"encoding":"msgpack"}}"#));
+        data.extend(length_prefixed(&msgpack::encode(&Json::Object(frame)).unwrap()));
+
+        let mut parser = MessageParser::new(MAX_FRAME_SIZE);
+        parser.push(&data);
+
+        match parser.pop() {
+            Some(Ok(Incoming::Hello(_))) => parser.set_encoding(Encoding::MessagePack),
+            other                        => panic!("expected a hello, got {:?}", other),
+        }
+        assert_eq!(frame_ids(&mut parser), vec!["1"]);
+    }
+
+    #[test]
+    fn transport_delimited_messages() {
+        let mut parser = MessageParser::new(40);
+        parser.push_message(br#"{"publisher":"p","id":1,"objects":[]}"#);
+        parser.push_message(br#"{"publisher":"p","id":2,"objects":[{}, {}]}"#);
+
+        let results = pop_all(&mut parser);
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        match results[1] {
+            Err(ref error) => assert!(error.fatal),
+            Ok(_)          => panic!("expected an error"),
+        }
+    }
+}
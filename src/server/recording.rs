@@ -0,0 +1,267 @@
+use types::message::{MessageIn, MessageOut, PROTOCOL_VERSION};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rustc_serialize::json;
+use rustc_serialize::json::{Json, ToJson};
+
+//  Bumped whenever the layout of the records changes
+pub const RECORDING_VERSION: u32 = 1;
+pub const RECORDING_FORMAT: &'static str = "show_and_tell/recording";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    //  Frames received from the publishers
+    In,
+    //  Input events sent back to them
+    Out,
+}
+
+impl Direction {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Direction::In  => "in",
+            Direction::Out => "out",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Direction> {
+        match name {
+            "in"  => Some(Direction::In),
+            "out" => Some(Direction::Out),
+            _     => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub time: f64,
+    pub direction: Direction,
+    pub publisher: String,
+    pub payload: Json,
+}
+
+#[derive(Debug)]
+pub struct Recording {
+    pub records: Vec<Record>,
+    //  The last record was cut off, like when the server was killed while writing it
+    pub truncated: bool,
+}
+
+//  Reads back a recording written by the Recorder, of this or an older version.
+//  Only an invalid last line is skipped, anywhere else it fails the whole file.
+pub fn load(path: &str) -> Result<Recording, String> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => if let Err(error) = file.read_to_string(&mut contents) {
+            return Err(format!("Failed to read {}: {}", path, error));
+        },
+        Err(error)   => return Err(format!("Failed to open {}: {}", path, error)),
+    }
+
+    let mut lines = contents.lines()
+        .enumerate()
+        .filter(|&(_, line)| !line.trim().is_empty())
+        .peekable();
+
+    let header = match lines.next().map(|(_, line)| Json::from_str(line)) {
+        Some(Ok(header)) => header,
+        _                => return Err(format!("{} is not a recording", path)),
+    };
+    if header.find("format").and_then(|format| format.as_string()) != Some(RECORDING_FORMAT) {
+        return Err(format!("{} is not a recording", path));
+    }
+    match header.find("version").and_then(|version| version.as_u64()) {
+        Some(version) if version <= RECORDING_VERSION as u64 => {},
+        _ => return Err(format!("{} was recorded in an unsupported version", path)),
+    }
+
+    let mut records: Vec<Record> = vec![];
+    let mut truncated = false;
+    while let Some((number, line)) = lines.next() {
+        let is_last = lines.peek().is_none();
+        let invalid = || format!("{}:{}: invalid record", path, number + 1);
+
+        let mut fields = match Json::from_str(line) {
+            Ok(Json::Object(fields)) => fields,
+            _ if is_last             => {
+                truncated = true;
+                break;
+            },
+            _                        => return Err(invalid()),
+        };
+
+        let time = fields.get("time").and_then(|time| time.as_f64());
+        let direction = fields.get("direction").and_then(|direction| direction.as_string()).and_then(Direction::from_name);
+        let publisher = fields.get("publisher").and_then(|publisher| publisher.as_string()).map(String::from);
+        let payload = fields.remove("payload");
+
+        match (time, direction, publisher, payload) {
+            (Some(time), Some(direction), Some(publisher), Some(payload)) => records.push(Record {
+                time:      time,
+                direction: direction,
+                publisher: publisher,
+                payload:   payload,
+            }),
+            _ => return Err(invalid()),
+        }
+    }
+
+    Ok(Recording {
+        records:   records,
+        truncated: truncated,
+    })
+}
+
+//  Writes the traffic as JSON lines: a header with the format version, then one record
+//  per message with its time in seconds since the Unix epoch. Payloads are stored
+//  in their normalized JSON form, whichever encoding the publisher used.
+pub struct Recorder {
+    file: File,
+    path: String,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> Result<Recorder, String> {
+        let file = File::create(path)
+            .map_err(|error| format!("Failed to open {}: {}", path, error))?;
+
+        let mut recorder = Recorder {
+            file: file,
+            path: String::from(path),
+        };
+
+        let header = format!("{{\"format\":{},\"version\":{},\"protocol\":{},\"started\":{:.6}}}\n",
+                             RECORDING_FORMAT.to_json(), RECORDING_VERSION, PROTOCOL_VERSION, now());
+        recorder.file.write_all(header.as_bytes())
+            .map_err(|error| format!("Failed to write {}: {}", path, error))?;
+
+        Ok(recorder)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn frame(&mut self, msg: &MessageIn) {
+        self.write(Direction::In, &msg.publisher, msg.to_json());
+    }
+
+    pub fn event(&mut self, msg: &MessageOut) {
+        self.write(Direction::Out, &msg.publisher, json::as_json(msg));
+    }
+
+    //  Every record is written at once, so an interrupted recording loses at most its last line
+    fn write<P: fmt::Display>(&mut self, direction: Direction, publisher: &str, payload: P) {
+        let record = format!("{{\"time\":{:.6},\"direction\":\"{}\",\"publisher\":{},\"payload\":{}}}\n",
+                             now(), direction.name(), publisher.to_json(), payload);
+
+        if let Err(error) = self.file.write_all(record.as_bytes()) {
+            println!("(Recorder) Failed to write {}: {}", self.path, error);
+        }
+    }
+}
+
+fn now() -> f64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs() as f64 + (time.subsec_nanos() as f64) / 1000000000.0,
+        Err(_)   => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    const HEADER: &'static str = r#"{"format":"show_and_tell/recording","version":1,"protocol":1,"started":0.0}"#;
+    const RECORD: &'static str = r#"{"time":1.5,"direction":"in","publisher":"p","payload":{"id":1}}"#;
+
+    //  Removed again when dropped
+    struct TempFile {
+        path: String,
+    }
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> TempFile {
+            let path = env::temp_dir().join(format!("recording-{}-{}", process::id(), name));
+            let file = TempFile {
+                path: path.to_string_lossy().into_owned(),
+            };
+            File::create(&file.path).unwrap().write_all(contents.as_bytes()).unwrap();
+            file
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn loads_records() {
+        let file = TempFile::new("valid", &format!("{}\n{}\n\n{}\n", HEADER, RECORD, RECORD.replace("\"in\"", "\"out\"")));
+        let recording = load(&file.path).unwrap();
+
+        assert!(!recording.truncated);
+        assert_eq!(recording.records.len(), 2);
+        assert_eq!(recording.records[0].time, 1.5);
+        assert_eq!(recording.records[0].direction, Direction::In);
+        assert_eq!(recording.records[0].publisher, "p");
+        assert_eq!(recording.records[1].direction, Direction::Out);
+    }
+
+    #[test]
+    fn skips_a_truncated_last_record() {
+        let file = TempFile::new("truncated", &format!("{}\n{}\n{}", HEADER, RECORD, &RECORD[..30]));
+        let recording = load(&file.path).unwrap();
+
+        assert!(recording.truncated);
+        assert_eq!(recording.records.len(), 1);
+    }
+
+    #[test]
+    fn invalid_record_in_the_middle() {
+        let file = TempFile::new("middle", &format!("{}\n{}\n{}\n{}\n", HEADER, &RECORD[..30], RECORD, RECORD));
+        assert!(load(&file.path).unwrap_err().ends_with(":2: invalid record"));
+
+        let file = TempFile::new("fields", &format!("{}\n{}\n{}\n", HEADER, r#"{"time":1.5,"direction":"sideways","publisher":"p","payload":{}}"#, RECORD));
+        assert!(load(&file.path).unwrap_err().ends_with(":2: invalid record"));
+    }
+
+    #[test]
+    fn wrong_format_or_version() {
+        let file = TempFile::new("format", &format!("{}\n{}\n", HEADER.replace("recording", "bookmarks"), RECORD));
+        assert!(load(&file.path).unwrap_err().ends_with("is not a recording"));
+
+        let file = TempFile::new("empty", "");
+        assert!(load(&file.path).unwrap_err().ends_with("is not a recording"));
+
+        let file = TempFile::new("version", &format!("{}\n{}\n", HEADER.replace("\"version\":1", "\"version\":2"), RECORD));
+        assert!(load(&file.path).unwrap_err().ends_with("was recorded in an unsupported version"));
+
+        assert!(load("/nonexistent/recording").unwrap_err().starts_with("Failed to open"));
+    }
+
+    #[test]
+    fn reads_back_what_the_recorder_wrote() {
+        let file = TempFile::new("recorder", "");
+        {
+            let mut recorder = Recorder::create(&file.path).unwrap();
+            let json = Json::from_str(r#"{"publisher":"p","id":"7","objects":[]}"#).unwrap();
+            recorder.frame(&MessageIn::from_json(json, None).unwrap());
+        }
+
+        let recording = load(&file.path).unwrap();
+        assert!(!recording.truncated);
+        assert_eq!(recording.records.len(), 1);
+        assert_eq!(recording.records[0].publisher, "p");
+        assert_eq!(recording.records[0].payload.find("id"), Some(&Json::String(String::from("7"))));
+    }
+}
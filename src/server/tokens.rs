@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+//  Shared secrets, publishers listed here have to present theirs in the hello
+pub struct Tokens {
+    secrets: HashMap<String, String>,
+}
+
+impl Tokens {
+    pub fn new() -> Tokens {
+        Tokens {
+            secrets: HashMap::<String, String>::new(),
+        }
+    }
+
+    //  Every line holds a publisher name and its token, separated by whitespace;
+    //  empty lines and lines starting with '#' are skipped
+    pub fn load(path: &Path) -> Result<Tokens, String> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => if let Err(error) = file.read_to_string(&mut contents) {
+                return Err(format!("Failed to read {}: {}", path.display(), error));
+            },
+            Err(error)   => return Err(format!("Failed to open {}: {}", path.display(), error)),
+        }
+
+        let mut tokens = Tokens::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match (words.get(0), words.get(1), words.len()) {
+                (Some(publisher), Some(token), 2) => {
+                    tokens.secrets.insert(publisher.to_string(), token.to_string());
+                },
+                _ => return Err(format!("{}:{}: expected \"publisher token\"", path.display(), number + 1)),
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    //  Publishers without a configured token are open to everyone
+    pub fn check(&self, publisher: &str, token: Option<&String>) -> bool {
+        match (self.secrets.get(publisher), token) {
+            (None, _)                   => true,
+            (Some(secret), Some(token)) => constant_time_eq(secret.as_bytes(), token.as_bytes()),
+            (Some(_), None)             => false,
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn load_text(name: &str, contents: &str) -> Result<Tokens, String> {
+        let path = env::temp_dir().join(format!("tokens-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let tokens = Tokens::load(&path);
+        let _ = fs::remove_file(&path);
+        tokens
+    }
+
+    #[test]
+    fn loads_publishers_and_tokens() {
+        let tokens = load_text("valid", "# publisher token\n\n  p   secret  \nq\tother\n").unwrap();
+        assert_eq!(tokens.len(), 2);
+
+        assert!(tokens.check("p", Some(&String::from("secret"))));
+        assert!(!tokens.check("p", Some(&String::from("other"))));
+        assert!(!tokens.check("p", Some(&String::from("secret2"))));
+        assert!(!tokens.check("p", None));
+        assert!(tokens.check("q", Some(&String::from("other"))));
+        assert!(tokens.check("open", None));
+    }
+
+    #[test]
+    fn invalid_lines() {
+        let error = load_text("missing", "p secret\nq\n").err().unwrap();
+        assert!(error.ends_with(":2: expected \"publisher token\""));
+
+        let error = load_text("extra", "p secret extra\n").err().unwrap();
+        assert!(error.ends_with(":1: expected \"publisher token\""));
+
+        assert!(Tokens::load(Path::new("/nonexistent/tokens")).err().unwrap().starts_with("Failed to open"));
+    }
+}
@@ -0,0 +1,425 @@
+use std::mem;
+use std::str;
+use std::collections::VecDeque;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use sha1::Sha1;
+
+const UPGRADE_METHOD: &'static [u8] = b"GET ";
+const HANDSHAKE_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HANDSHAKE_LENGTH: usize = 8192;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+pub const STATUS_PROTOCOL_ERROR: u16 = 1002;
+pub const STATUS_POLICY_VIOLATION: u16 = 1008;
+pub const STATUS_TOO_BIG: u16 = 1009;
+
+//  Tells whether the connection starts with a WebSocket upgrade request,
+//  None if there are not enough bytes to decide yet
+pub fn detect(prefix: &[u8]) -> Option<bool> {
+    if prefix.len() >= UPGRADE_METHOD.len() {
+        Some(prefix.starts_with(UPGRADE_METHOD))
+    } else if UPGRADE_METHOD.starts_with(prefix) {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+pub fn encode_text(payload: &[u8]) -> Vec<u8> {
+    encode_frame(OPCODE_TEXT, payload)
+}
+
+pub fn encode_binary(payload: &[u8]) -> Vec<u8> {
+    encode_frame(OPCODE_BINARY, payload)
+}
+
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+
+    let length = payload.len();
+    if length < 126 {
+        frame.push(length as u8);
+    } else if length <= 0xFFFF {
+        frame.push(126);
+        frame.push((length >> 8) as u8);
+        frame.push(length as u8);
+    } else {
+        frame.push(127);
+        for shift in (0..8).rev() {
+            frame.push(((length as u64) >> (8 * shift)) as u8);
+        }
+    }
+
+    frame.extend(payload);
+    frame
+}
+
+pub struct WebSocket {
+    upgraded: bool,
+    closed: bool,
+    buffer: Vec<u8>,
+    fragments: Vec<u8>,
+    messages: VecDeque<Vec<u8>>,
+    output: Vec<u8>,
+    max_message_size: usize,
+}
+
+impl WebSocket {
+    pub fn new(max_message_size: usize) -> WebSocket {
+        WebSocket {
+            upgraded: false,
+            closed: false,
+            buffer: vec![],
+            fragments: vec![],
+            messages: VecDeque::<Vec<u8>>::new(),
+            output: vec![],
+            max_message_size: max_message_size,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.buffer.extend(data);
+
+        if !self.upgraded {
+            match self.buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                Some(end) => {
+                    let request = self.buffer[..end].to_vec();
+                    self.buffer = self.buffer.split_off(end + 4);
+                    self.upgrade(&request)?;
+                },
+                None if self.buffer.len() > MAX_HANDSHAKE_LENGTH => {
+                    return self.reject("WebSocket handshake is too long");
+                },
+                None => return Ok(()),
+            }
+        }
+
+        self.decode_frames()
+    }
+
+    fn upgrade(&mut self, request: &[u8]) -> Result<(), String> {
+        let request = match str::from_utf8(request) {
+            Ok(text) => text,
+            Err(_)   => return self.reject("WebSocket handshake is not utf8-encoded"),
+        };
+
+        let key = request.split("\r\n")
+            .skip(1)
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("sec-websocket-key") => {
+                        Some(value.trim())
+                    },
+                    _ => None,
+                }
+            })
+            .next();
+
+        let key = match key {
+            Some(key) => key,
+            None      => return self.reject("WebSocket handshake without Sec-WebSocket-Key"),
+        };
+
+        let mut hasher = Sha1::new();
+        hasher.update(key.as_bytes());
+        hasher.update(HANDSHAKE_GUID.as_bytes());
+        let accept = hasher.digest().bytes().to_base64(STANDARD);
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept
+        );
+        self.output.extend(response.as_bytes());
+        self.upgraded = true;
+
+        Ok(())
+    }
+
+    fn reject(&mut self, reason: &str) -> Result<(), String> {
+        self.output.extend(b"HTTP/1.1 400 Bad Request\r\n\r\n".iter());
+        self.closed = true;
+        Err(String::from(reason))
+    }
+
+    fn decode_frames(&mut self) -> Result<(), String> {
+        while !self.closed && self.buffer.len() >= 2 {
+            let fin = self.buffer[0] & 0x80 != 0;
+            let opcode = self.buffer[0] & 0x0F;
+            let masked = self.buffer[1] & 0x80 != 0;
+
+            let (length, header_length): (usize, usize) = match self.buffer[1] & 0x7F {
+                126 => {
+                    if self.buffer.len() < 4 {
+                        break;
+                    }
+                    (((self.buffer[2] as usize) << 8) | (self.buffer[3] as usize), 4)
+                },
+                127 => {
+                    if self.buffer.len() < 10 {
+                        break;
+                    }
+                    let length = self.buffer[2..10].iter().fold(0u64, |length, &byte| (length << 8) | byte as u64);
+                    (length as usize, 10)
+                },
+                length => (length as usize, 2),
+            };
+
+            if !masked {
+                return self.fail(STATUS_PROTOCOL_ERROR, "WebSocket frames sent by clients must be masked");
+            }
+
+            //  Checked before the payload arrives, so that it never gets buffered
+            if length > self.max_message_size || self.fragments.len() + length > self.max_message_size {
+                return self.fail(STATUS_TOO_BIG, "WebSocket message is too large");
+            }
+
+            let payload_start = header_length + 4;
+            let frame_length = payload_start + length;
+            if self.buffer.len() < frame_length {
+                break;
+            }
+
+            let mask = [
+                self.buffer[header_length],
+                self.buffer[header_length + 1],
+                self.buffer[header_length + 2],
+                self.buffer[header_length + 3],
+            ];
+            let payload: Vec<u8> = self.buffer[payload_start..frame_length].iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4])
+                .collect();
+            self.buffer.drain(..frame_length);
+
+            match opcode {
+                OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                    self.fragments.extend(payload);
+                    if fin {
+                        let message = mem::replace(&mut self.fragments, vec![]);
+                        self.messages.push_back(message);
+                    }
+                },
+                OPCODE_CLOSE => {
+                    self.output.extend(encode_frame(OPCODE_CLOSE, &payload[..payload.len().min(2)]));
+                    self.closed = true;
+                },
+                OPCODE_PING => self.output.extend(encode_frame(OPCODE_PONG, &payload)),
+                OPCODE_PONG => {},
+                _ => {
+                    let reason = format!("Unknown WebSocket opcode {}", opcode);
+                    return self.fail(STATUS_PROTOCOL_ERROR, &reason);
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fail(&mut self, status: u16, reason: &str) -> Result<(), String> {
+        self.close(status, reason);
+        Err(String::from(reason))
+    }
+
+    //  Control frames are limited to 125 bytes, so the reason may get truncated
+    pub fn close(&mut self, status: u16, reason: &str) {
+        if self.closed {
+            return;
+        }
+
+        let mut payload = vec![(status >> 8) as u8, status as u8];
+        let mut reason_length = reason.len().min(123);
+        while !reason.is_char_boundary(reason_length) {
+            reason_length -= 1;
+        }
+        payload.extend(reason[..reason_length].as_bytes());
+
+        self.output.extend(encode_frame(OPCODE_CLOSE, &payload));
+        self.closed = true;
+    }
+
+    //  Bytes received but not assembled into messages yet
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() + self.fragments.len()
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.messages.pop_front()
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        mem::replace(&mut self.output, vec![])
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_MESSAGE_SIZE: usize = 1 << 20;
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    fn handshake(key: &str) -> Vec<u8> {
+        format!("GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nSec-WebSocket-Key: {}\r\n\r\n", key).into_bytes()
+    }
+
+    fn upgraded() -> WebSocket {
+        let mut socket = WebSocket::new(MAX_MESSAGE_SIZE);
+        socket.push(&handshake("dGhlIHNhbXBsZSBub25jZQ==")).unwrap();
+        socket.take_output();
+        socket
+    }
+
+    //  Frames as clients send them, always masked
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode_frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        frame[1] |= 0x80;
+
+        let header_length = frame.len() - payload.len();
+        frame.truncate(header_length);
+        frame.extend(MASK.iter());
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ MASK[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn detects_upgrade_requests() {
+        assert_eq!(detect(b""), None);
+        assert_eq!(detect(b"GE"), None);
+        assert_eq!(detect(b"GET /"), Some(true));
+        assert_eq!(detect(b"{\"hello\""), Some(false));
+        assert_eq!(detect(b"G{"), Some(false));
+    }
+
+    #[test]
+    fn handshake_accepts_the_key() {
+        let mut socket = WebSocket::new(MAX_MESSAGE_SIZE);
+        let request = handshake("dGhlIHNhbXBsZSBub25jZQ==");
+        socket.push(&request[..10]).unwrap();
+        assert!(socket.take_output().is_empty());
+
+        socket.push(&request[10..]).unwrap();
+        let response = String::from_utf8(socket.take_output()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn handshake_without_key() {
+        let mut socket = WebSocket::new(MAX_MESSAGE_SIZE);
+        assert!(socket.push(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").is_err());
+        assert!(socket.is_closed());
+        assert!(socket.take_output().starts_with(b"HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn frames_pipelined_with_the_handshake() {
+        let mut socket = WebSocket::new(MAX_MESSAGE_SIZE);
+        let mut data = handshake("dGhlIHNhbXBsZSBub25jZQ==");
+        data.extend(client_frame(true, OPCODE_TEXT, b"{}"));
+        socket.push(&data).unwrap();
+        assert_eq!(socket.pop(), Some(b"{}".to_vec()));
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let mut socket = upgraded();
+        let frame = client_frame(true, OPCODE_TEXT, b"{\"id\":1}");
+        for byte in &frame {
+            assert_eq!(socket.pop(), None);
+            socket.push(&[*byte]).unwrap();
+        }
+        assert_eq!(socket.pop(), Some(b"{\"id\":1}".to_vec()));
+        assert_eq!(socket.buffered(), 0);
+    }
+
+    #[test]
+    fn extended_lengths() {
+        let mut socket = upgraded();
+        let medium = vec![b'm'; 300];
+        let large = vec![b'l'; 70000];
+        let mut data = client_frame(true, OPCODE_BINARY, &medium);
+        data.extend(client_frame(true, OPCODE_BINARY, &large));
+        socket.push(&data).unwrap();
+        assert_eq!(socket.pop(), Some(medium));
+        assert_eq!(socket.pop(), Some(large));
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let mut socket = upgraded();
+        socket.push(&client_frame(false, OPCODE_TEXT, b"{\"id\":")).unwrap();
+        assert_eq!(socket.pop(), None);
+
+        //  Control frames may come between the fragments
+        socket.push(&client_frame(true, OPCODE_PING, b"ping")).unwrap();
+        socket.push(&client_frame(true, OPCODE_CONTINUATION, b"1}")).unwrap();
+        assert_eq!(socket.pop(), Some(b"{\"id\":1}".to_vec()));
+        assert_eq!(socket.take_output(), encode_frame(OPCODE_PONG, b"ping"));
+    }
+
+    #[test]
+    fn unmasked_frame() {
+        let mut socket = upgraded();
+        assert!(socket.push(&encode_text(b"{}")).is_err());
+        assert!(socket.is_closed());
+        assert_eq!(socket.pop(), None);
+        assert_eq!(&socket.take_output()[2..4], &[0x03, 0xEA]);
+    }
+
+    #[test]
+    fn oversize_length_before_the_payload() {
+        let mut socket = WebSocket::new(16);
+        socket.push(&handshake("dGhlIHNhbXBsZSBub25jZQ==")).unwrap();
+        socket.take_output();
+
+        let header = [0x82, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert!(socket.push(&header).is_err());
+        assert!(socket.is_closed());
+        assert_eq!(&socket.take_output()[2..4], &[0x03, 0xF1]);
+    }
+
+    #[test]
+    fn oversize_fragmented_message() {
+        let mut socket = WebSocket::new(16);
+        socket.push(&handshake("dGhlIHNhbXBsZSBub25jZQ==")).unwrap();
+        socket.push(&client_frame(false, OPCODE_TEXT, &[b'a'; 10])).unwrap();
+        assert!(socket.push(&client_frame(true, OPCODE_CONTINUATION, &[b'a'; 10])).is_err());
+        assert!(socket.is_closed());
+    }
+
+    #[test]
+    fn close_is_echoed() {
+        let mut socket = upgraded();
+        socket.push(&client_frame(true, OPCODE_CLOSE, &[0x03, 0xE8, b'b', b'y', b'e'])).unwrap();
+        assert!(socket.is_closed());
+        assert_eq!(socket.take_output(), encode_frame(OPCODE_CLOSE, &[0x03, 0xE8]));
+
+        socket.push(&client_frame(true, OPCODE_TEXT, b"{}")).unwrap();
+        assert_eq!(socket.pop(), None);
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut socket = upgraded();
+        assert!(socket.push(&client_frame(true, 0x3, b"")).is_err());
+        assert!(socket.is_closed());
+    }
+}
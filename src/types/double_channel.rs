@@ -21,6 +21,11 @@ impl<TOut, TIn> Endpoint<TOut, TIn> {
     pub fn try_recv(&self) -> Result<TIn, TryRecvError> {
         self.link_in.try_recv()
     }
+
+    //  Lets the two directions be handled by different threads
+    pub fn split(self) -> (Sender<TOut>, Receiver<TIn>) {
+        (self.link_out, self.link_in)
+    }
 }
 
 pub fn channel<T1, T2>() -> (Endpoint<T1, T2>, Endpoint<T2, T1>) {
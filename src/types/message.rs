@@ -1,18 +1,448 @@
+use types::msgpack;
+
 use std::collections::HashMap;
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use rustc_serialize::json;
+use rustc_serialize::json::{Json, ToJson};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub type Object = HashMap<String, Json>;
 
-pub type Object = HashMap<String, String>;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "json"    => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MessagePack),
+            _         => None,
+        }
+    }
+}
 
-#[derive(RustcDecodable, RustcEncodable, Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    //  Replaces the whole scene
+    Keyframe,
+    //  Inserts or updates objects by id and removes the listed ones
+    Delta,
+}
+
+#[derive(Debug, Clone)]
 pub struct MessageIn {
     pub publisher: String,
     pub id: String,
+    pub kind: FrameKind,
     pub objects: Vec<Object>,
+    pub removed: Vec<u32>,
+    pub removed_permanent: Vec<u32>,
+    pub clear_permanent: bool,
+    pub new_game: bool,
 }
 
-#[derive(RustcDecodable, RustcEncodable, Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    KeyPressed,
+    KeyReleased,
+    MouseClick,
+    Scroll,
+    //  The viewer has shown the frame and asks for the next one, in lockstep mode
+    NextFrame,
+}
+
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EventKind::KeyPressed  => "key_pressed",
+            EventKind::KeyReleased => "key_released",
+            EventKind::MouseClick  => "mouse_click",
+            EventKind::Scroll      => "scroll",
+            EventKind::NextFrame   => "next_frame",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<EventKind> {
+        match name {
+            "key_pressed"  => Some(EventKind::KeyPressed),
+            "key_released" => Some(EventKind::KeyReleased),
+            "mouse_click"  => Some(EventKind::MouseClick),
+            "scroll"       => Some(EventKind::Scroll),
+            "next_frame"   => Some(EventKind::NextFrame),
+            _              => None,
+        }
+    }
+}
+
+impl Encodable for EventKind {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(self.name())
+    }
+}
+
+impl Decodable for EventKind {
+    fn decode<D: Decoder>(d: &mut D) -> Result<EventKind, D::Error> {
+        let name = d.read_str()?;
+        match EventKind::from_name(&name) {
+            Some(kind) => Ok(kind),
+            None       => Err(d.error(&format!("unknown event kind \"{}\"", name))),
+        }
+    }
+}
+
+//  State of the modifier keys when the event happened
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, Copy, Default)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+//  Input event of a visualization, the fields not concerning its kind are left empty
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
 pub struct MessageOut {
+    pub publisher: String,
+    //  Frame shown when the event happened
+    pub id: String,
+    pub kind: EventKind,
+    //  Object under the cursor, or the selected one for keys handled by its type
+    pub object_id: Option<u32>,
+    pub key_code: Option<String>,
+    pub button: Option<String>,
+    //  Cursor position on the z = 0 plane, in world coordinates
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub scroll: Option<f32>,
+    pub modifiers: Modifiers,
+}
+
+//  The only input event of protocol version 1, a key press handled by the type of the
+//  selected object. Connections without the "input-events" feature get nothing else.
+#[derive(RustcEncodable, Debug, Clone)]
+pub struct LegacyEvent {
     pub publisher: String,
     pub id: String,
     pub object_id: u32,
     pub key_code: String,
 }
+
+impl MessageOut {
+    pub fn to_legacy(&self) -> Option<LegacyEvent> {
+        match (self.kind, self.object_id, self.key_code.as_ref()) {
+            (EventKind::KeyPressed, Some(object_id), Some(key_code)) => Some(LegacyEvent {
+                publisher: self.publisher.clone(),
+                id:        self.id.clone(),
+                object_id: object_id,
+                key_code:  key_code.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct MessageError {
+    pub error: String,
+    pub publisher: Option<String>,
+    pub id: Option<String>,
+    pub offset: Option<usize>,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct Hello {
+    pub version: u32,
+    pub publisher: Option<String>,
+    pub encoding: Option<String>,
+    pub features: Option<Vec<String>>,
+    pub token: Option<String>,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct Welcome {
+    pub version: u32,
+    pub encoding: String,
+    pub features: Vec<String>,
+}
+
+//  Everything the clients can send to the server
+#[derive(Debug)]
+pub enum Incoming {
+    Hello(Hello),
+    Frame(MessageIn),
+    //  Starts or stops receiving the input events of a publisher
+    Subscribe(String),
+    Unsubscribe(String),
+    //  Keeps an otherwise quiet connection from timing out
+    Heartbeat,
+}
+
+//  Everything the server sends back to the connected clients
+#[derive(Debug, Clone)]
+pub enum Reply {
+    Event(MessageOut),
+    Error(MessageError),
+    Welcome(Welcome),
+}
+
+impl Incoming {
+    pub fn from_json(json: Json, default_publisher: Option<&String>) -> Result<Incoming, String> {
+        let control = match json {
+            Json::Object(ref fields) => ["hello", "subscribe", "unsubscribe", "heartbeat"].iter()
+                .filter_map(|key| fields.get(*key).map(|value| (*key, value.clone())))
+                .next(),
+            _                        => None,
+        };
+
+        match control {
+            Some(("hello", hello)) => {
+                let mut decoder = json::Decoder::new(hello);
+                Hello::decode(&mut decoder)
+                    .map(Incoming::Hello)
+                    .map_err(|error| format!("invalid hello message ({})", error))
+            },
+            Some(("subscribe", Json::String(publisher)))   => Ok(Incoming::Subscribe(publisher)),
+            Some(("unsubscribe", Json::String(publisher))) => Ok(Incoming::Unsubscribe(publisher)),
+            Some(("heartbeat", _))                         => Ok(Incoming::Heartbeat),
+            Some((key, _))                                 => Err(format!("\"{}\" must be a publisher name", key)),
+            None                                           => MessageIn::from_json(json, default_publisher).map(Incoming::Frame),
+        }
+    }
+}
+
+impl Reply {
+    pub fn publisher(&self) -> Option<&String> {
+        match *self {
+            Reply::Event(ref msg) => Some(&msg.publisher),
+            Reply::Error(ref msg) => msg.publisher.as_ref(),
+            Reply::Welcome(_)     => None,
+        }
+    }
+
+    //  Replies are told apart by their fields, the way clients receive them
+    pub fn from_json(json: Json) -> Result<Reply, String> {
+        let (is_error, welcome) = match json {
+            Json::Object(ref fields) => (fields.contains_key("error"), fields.get("welcome").cloned()),
+            _                        => return Err(String::from("reply is not a JSON object")),
+        };
+
+        let reply = match welcome {
+            Some(welcome)    => Welcome::decode(&mut json::Decoder::new(welcome)).map(Reply::Welcome),
+            None if is_error => MessageError::decode(&mut json::Decoder::new(json)).map(Reply::Error),
+            None             => MessageOut::decode(&mut json::Decoder::new(json)).map(Reply::Event),
+        };
+        reply.map_err(|error| format!("invalid reply ({})", error))
+    }
+
+    pub fn serialize(&self, encoding: Encoding) -> Vec<u8> {
+        serialize(self, encoding)
+    }
+}
+
+pub fn serialize<T: Encodable>(value: &T, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Json        => json::as_json(value).to_string().into_bytes(),
+        Encoding::MessagePack => msgpack::encode(value).expect("Reply is too large for MessagePack"),
+    }
+}
+
+impl Encodable for Reply {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        match *self {
+            Reply::Event(ref msg)   => msg.encode(s),
+            Reply::Error(ref msg)   => msg.encode(s),
+            Reply::Welcome(ref msg) => s.emit_map(1, |s| {
+                s.emit_map_elt_key(0, |s| s.emit_str("welcome"))?;
+                s.emit_map_elt_val(0, |s| msg.encode(s))
+            }),
+        }
+    }
+}
+
+impl MessageIn {
+    pub fn from_json(json: Json, default_publisher: Option<&String>) -> Result<MessageIn, String> {
+        let mut fields = match json {
+            Json::Object(fields) => fields,
+            _                    => return Err(String::from("message is not a JSON object")),
+        };
+
+        let publisher = match fields.remove("publisher") {
+            Some(Json::String(publisher)) => publisher,
+            Some(_)                       => return Err(String::from("\"publisher\" must be a string")),
+            None                          => match default_publisher {
+                Some(publisher) => publisher.clone(),
+                None            => return Err(String::from("missing field \"publisher\"")),
+            },
+        };
+
+        let id = match fields.remove("id") {
+            Some(ref value) if is_scalar(value) => value_to_string(value),
+            Some(_)                             => return Err(String::from("\"id\" must be a string or a number")),
+            None                                => return Err(String::from("missing field \"id\"")),
+        };
+
+        let kind = match fields.remove("kind") {
+            None                                               => FrameKind::Keyframe,
+            Some(Json::String(ref kind)) if kind == "keyframe" => FrameKind::Keyframe,
+            Some(Json::String(ref kind)) if kind == "delta"    => FrameKind::Delta,
+            Some(_)                                            => return Err(String::from("\"kind\" must be either \"keyframe\" or \"delta\"")),
+        };
+
+        let objects = match fields.remove("objects") {
+            Some(Json::Array(array)) => {
+                let mut objects: Vec<Object> = vec![];
+                for value in array {
+                    match value {
+                        Json::Object(attributes) => objects.push(attributes.into_iter().collect()),
+                        _                        => return Err(String::from("\"objects\" must contain only objects")),
+                    }
+                }
+                objects
+            },
+            Some(_)                          => return Err(String::from("\"objects\" must be an array")),
+            None if kind == FrameKind::Delta => vec![],
+            None                             => return Err(String::from("missing field \"objects\"")),
+        };
+
+        if kind == FrameKind::Delta {
+            let has_id = |object: &Object, key: &str| object.get(key).and_then(value_as_u32).is_some();
+            if objects.iter().any(|object| !has_id(object, "id") && !has_id(object, "permanent_id")) {
+                return Err(String::from("objects in a delta frame must have an \"id\""));
+            }
+        }
+
+        let removed = take_ids(&mut fields, "removed")?;
+        let removed_permanent = take_ids(&mut fields, "removed_permanent")?;
+        let clear_permanent = take_flag(&mut fields, "clear_permanent")?;
+        let new_game = take_flag(&mut fields, "new_game")?;
+
+        Ok(MessageIn {
+            publisher:         publisher,
+            id:                id,
+            kind:              kind,
+            objects:           objects,
+            removed:           removed,
+            removed_permanent: removed_permanent,
+            clear_permanent:   clear_permanent,
+            new_game:          new_game,
+        })
+    }
+}
+
+//  The inverse of from_json, fields holding their defaults are left out
+impl ToJson for MessageIn {
+    fn to_json(&self) -> Json {
+        let kind = match self.kind {
+            FrameKind::Keyframe => "keyframe",
+            FrameKind::Delta    => "delta",
+        };
+        let objects = self.objects.iter()
+            .map(|object| Json::Object(object.clone().into_iter().collect()))
+            .collect();
+
+        let mut fields = json::Object::new();
+        fields.insert(String::from("publisher"), self.publisher.to_json());
+        fields.insert(String::from("id"), self.id.to_json());
+        fields.insert(String::from("kind"), kind.to_json());
+        fields.insert(String::from("objects"), Json::Array(objects));
+
+        if !self.removed.is_empty() {
+            fields.insert(String::from("removed"), self.removed.to_json());
+        }
+        if !self.removed_permanent.is_empty() {
+            fields.insert(String::from("removed_permanent"), self.removed_permanent.to_json());
+        }
+        if self.clear_permanent {
+            fields.insert(String::from("clear_permanent"), Json::Boolean(true));
+        }
+        if self.new_game {
+            fields.insert(String::from("new_game"), Json::Boolean(true));
+        }
+
+        Json::Object(fields)
+    }
+}
+
+//  Goes through to_json, so that there is a single wire format
+impl Encodable for MessageIn {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        self.to_json().encode(s)
+    }
+}
+
+fn take_ids(fields: &mut json::Object, key: &str) -> Result<Vec<u32>, String> {
+    match fields.remove(key) {
+        Some(Json::Array(array)) => {
+            let mut ids: Vec<u32> = vec![];
+            for value in array {
+                match value_as_u32(&value) {
+                    Some(id) => ids.push(id),
+                    None     => return Err(format!("\"{}\" must contain only object ids", key)),
+                }
+            }
+            Ok(ids)
+        },
+        Some(_) => Err(format!("\"{}\" must be an array", key)),
+        None    => Ok(vec![]),
+    }
+}
+
+fn take_flag(fields: &mut json::Object, key: &str) -> Result<bool, String> {
+    match fields.remove(key) {
+        Some(Json::Boolean(flag)) => Ok(flag),
+        Some(_)                   => Err(format!("\"{}\" must be a boolean", key)),
+        None                      => Ok(false),
+    }
+}
+
+//  Attributes may hold any JSON value, but older clients send everything as strings,
+//  so numeric accessors accept both representations
+
+fn is_scalar(value: &Json) -> bool {
+    match *value {
+        Json::Array(_) | Json::Object(_) | Json::Null => false,
+        _                                              => true,
+    }
+}
+
+pub fn value_to_string(value: &Json) -> String {
+    match *value {
+        Json::String(ref text) => text.clone(),
+        _                      => value.to_string(),
+    }
+}
+
+pub fn value_as_f32(value: &Json) -> Option<f32> {
+    match *value {
+        Json::String(ref text) => text.trim().parse::<f32>().ok(),
+        _                      => value.as_f64().map(|number| number as f32),
+    }
+}
+
+pub fn value_as_u32(value: &Json) -> Option<u32> {
+    let max = u32::max_value() as u64;
+
+    match *value {
+        Json::String(ref text)             => text.trim().parse::<u32>().ok(),
+        Json::U64(number) if number <= max => Some(number as u32),
+        _                                  => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_frames_are_accepted() {
+        let json = Json::from_str(r#"{"publisher":"p","id":"1","kind":"delta","objects":[{"id":2}],"removed":[3],"new_game":true}"#).unwrap();
+        let msg = MessageIn::from_json(json.clone(), None).unwrap();
+
+        let encoded = Json::from_str(&json::encode(&msg).unwrap()).unwrap();
+        assert_eq!(encoded, json);
+        assert!(MessageIn::from_json(encoded, None).is_ok());
+    }
+}
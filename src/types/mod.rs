@@ -1,5 +1,6 @@
 pub mod message;
 pub mod double_channel;
+pub mod msgpack;
 
 #[derive(Debug, Clone)]
 pub enum Geometry {
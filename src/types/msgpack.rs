@@ -0,0 +1,489 @@
+use std::collections::BTreeMap;
+use std::str;
+use rustc_serialize;
+use rustc_serialize::Encodable;
+use rustc_serialize::json::Json;
+
+//  MessagePack counterpart of the JSON encoding. Decoding produces the same
+//  Json values the JSON parser does, so frames go through the same validation
+
+const MAX_DEPTH: usize = 256;
+
+pub type EncodeResult = Result<(), String>;
+
+#[derive(Debug, Clone)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub reason: String,
+}
+
+pub fn encode<T: Encodable>(value: &T) -> Result<Vec<u8>, String> {
+    let mut output: Vec<u8> = vec![];
+    value.encode(&mut Encoder::new(&mut output))?;
+    Ok(output)
+}
+
+pub fn decode(data: &[u8]) -> Result<Json, DecodeError> {
+    let mut decoder = Decoder {
+        data:     data,
+        position: 0,
+    };
+
+    let value = decoder.read_value(0)?;
+    if decoder.position != data.len() {
+        return Err(decoder.error(decoder.position, "unexpected bytes after the value"));
+    }
+
+    Ok(value)
+}
+
+pub struct Encoder<'a> {
+    output: &'a mut Vec<u8>,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(output: &'a mut Vec<u8>) -> Encoder<'a> {
+        Encoder {
+            output: output,
+        }
+    }
+
+    fn write_be(&mut self, value: u64, bytes: usize) {
+        for shift in (0..bytes).rev() {
+            self.output.push((value >> (8 * shift)) as u8);
+        }
+    }
+
+    //  Writes the smallest header able to hold the length
+    fn write_header(&mut self, length: usize, fixed: u8, fixed_max: usize, markers: (Option<u8>, u8, u8)) -> EncodeResult {
+        let (marker_8, marker_16, marker_32) = markers;
+
+        if length <= fixed_max {
+            self.output.push(fixed | length as u8);
+        } else if let (Some(marker), true) = (marker_8, length <= 0xFF) {
+            self.output.push(marker);
+            self.write_be(length as u64, 1);
+        } else if length <= 0xFFFF {
+            self.output.push(marker_16);
+            self.write_be(length as u64, 2);
+        } else if length as u64 <= 0xFFFF_FFFF {
+            self.output.push(marker_32);
+            self.write_be(length as u64, 4);
+        } else {
+            return Err(format!("length {} is too large for MessagePack", length));
+        }
+        Ok(())
+    }
+
+    fn write_array_header(&mut self, length: usize) -> EncodeResult {
+        self.write_header(length, 0x90, 0x0F, (None, 0xDC, 0xDD))
+    }
+
+    fn write_map_header(&mut self, length: usize) -> EncodeResult {
+        self.write_header(length, 0x80, 0x0F, (None, 0xDE, 0xDF))
+    }
+
+    fn write_uint(&mut self, value: u64) -> EncodeResult {
+        if value < 0x80 {
+            self.output.push(value as u8);
+        } else if value <= 0xFF {
+            self.output.push(0xCC);
+            self.write_be(value, 1);
+        } else if value <= 0xFFFF {
+            self.output.push(0xCD);
+            self.write_be(value, 2);
+        } else if value <= 0xFFFF_FFFF {
+            self.output.push(0xCE);
+            self.write_be(value, 4);
+        } else {
+            self.output.push(0xCF);
+            self.write_be(value, 8);
+        }
+        Ok(())
+    }
+
+    fn write_int(&mut self, value: i64) -> EncodeResult {
+        if value >= 0 {
+            return self.write_uint(value as u64);
+        }
+
+        if value >= -32 {
+            self.output.push(value as u8);
+        } else if value >= i8::min_value() as i64 {
+            self.output.push(0xD0);
+            self.write_be(value as u64, 1);
+        } else if value >= i16::min_value() as i64 {
+            self.output.push(0xD1);
+            self.write_be(value as u64, 2);
+        } else if value >= i32::min_value() as i64 {
+            self.output.push(0xD2);
+            self.write_be(value as u64, 4);
+        } else {
+            self.output.push(0xD3);
+            self.write_be(value as u64, 8);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> rustc_serialize::Encoder for Encoder<'a> {
+    type Error = String;
+
+    fn emit_nil(&mut self) -> EncodeResult {
+        self.output.push(0xC0);
+        Ok(())
+    }
+
+    fn emit_usize(&mut self, v: usize) -> EncodeResult { self.write_uint(v as u64) }
+    fn emit_u64(&mut self, v: u64) -> EncodeResult { self.write_uint(v) }
+    fn emit_u32(&mut self, v: u32) -> EncodeResult { self.write_uint(v as u64) }
+    fn emit_u16(&mut self, v: u16) -> EncodeResult { self.write_uint(v as u64) }
+    fn emit_u8(&mut self, v: u8) -> EncodeResult { self.write_uint(v as u64) }
+
+    fn emit_isize(&mut self, v: isize) -> EncodeResult { self.write_int(v as i64) }
+    fn emit_i64(&mut self, v: i64) -> EncodeResult { self.write_int(v) }
+    fn emit_i32(&mut self, v: i32) -> EncodeResult { self.write_int(v as i64) }
+    fn emit_i16(&mut self, v: i16) -> EncodeResult { self.write_int(v as i64) }
+    fn emit_i8(&mut self, v: i8) -> EncodeResult { self.write_int(v as i64) }
+
+    fn emit_bool(&mut self, v: bool) -> EncodeResult {
+        self.output.push(if v { 0xC3 } else { 0xC2 });
+        Ok(())
+    }
+
+    fn emit_f64(&mut self, v: f64) -> EncodeResult {
+        self.output.push(0xCB);
+        self.write_be(v.to_bits(), 8);
+        Ok(())
+    }
+
+    fn emit_f32(&mut self, v: f32) -> EncodeResult {
+        self.output.push(0xCA);
+        self.write_be(v.to_bits() as u64, 4);
+        Ok(())
+    }
+
+    fn emit_char(&mut self, v: char) -> EncodeResult {
+        self.emit_str(&v.to_string())
+    }
+
+    fn emit_str(&mut self, v: &str) -> EncodeResult {
+        self.write_header(v.len(), 0xA0, 0x1F, (Some(0xD9), 0xDA, 0xDB))?;
+        self.output.extend(v.as_bytes());
+        Ok(())
+    }
+
+    fn emit_enum<F>(&mut self, _name: &str, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+
+    //  Same layout as the JSON encoder: unit variants become their name,
+    //  the others a single-entry map from the name to the arguments
+    fn emit_enum_variant<F>(&mut self, name: &str, _id: usize, len: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        if len == 0 {
+            return self.emit_str(name);
+        }
+
+        self.write_map_header(1)?;
+        self.emit_str(name)?;
+        self.write_array_header(len)?;
+        f(self)
+    }
+
+    fn emit_enum_variant_arg<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_enum_struct_variant<F>(&mut self, name: &str, _id: usize, len: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        self.write_map_header(1)?;
+        self.emit_str(name)?;
+        self.write_map_header(len)?;
+        f(self)
+    }
+
+    fn emit_enum_struct_variant_field<F>(&mut self, name: &str, _idx: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        self.emit_str(name)?;
+        f(self)
+    }
+
+    fn emit_struct<F>(&mut self, _name: &str, len: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        self.write_map_header(len)?;
+        f(self)
+    }
+
+    fn emit_struct_field<F>(&mut self, name: &str, _idx: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        self.emit_str(name)?;
+        f(self)
+    }
+
+    fn emit_tuple<F>(&mut self, len: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        self.write_array_header(len)?;
+        f(self)
+    }
+
+    fn emit_tuple_arg<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_tuple_struct<F>(&mut self, _name: &str, len: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        self.write_array_header(len)?;
+        f(self)
+    }
+
+    fn emit_tuple_struct_arg<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_option<F>(&mut self, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_option_none(&mut self) -> EncodeResult {
+        self.emit_nil()
+    }
+
+    fn emit_option_some<F>(&mut self, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_seq<F>(&mut self, len: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        self.write_array_header(len)?;
+        f(self)
+    }
+
+    fn emit_seq_elt<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_map<F>(&mut self, len: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        self.write_map_header(len)?;
+        f(self)
+    }
+
+    fn emit_map_elt_key<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+
+    fn emit_map_elt_val<F>(&mut self, _idx: usize, f: F) -> EncodeResult
+        where F: FnOnce(&mut Self) -> EncodeResult {
+        f(self)
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, offset: usize, reason: &str) -> DecodeError {
+        DecodeError {
+            offset: offset,
+            reason: format!("invalid MessagePack value ({})", reason),
+        }
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() - self.position < length {
+            return Err(self.error(self.data.len(), "unexpected end of data"));
+        }
+
+        let bytes = &self.data[self.position..(self.position + length)];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn read_uint(&mut self, bytes: usize) -> Result<u64, DecodeError> {
+        let bytes = self.read_bytes(bytes)?;
+        Ok(bytes.iter().fold(0u64, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    fn read_length(&mut self, bytes: usize) -> Result<usize, DecodeError> {
+        self.read_uint(bytes).map(|length| length as usize)
+    }
+
+    fn read_value(&mut self, depth: usize) -> Result<Json, DecodeError> {
+        let start = self.position;
+        if depth > MAX_DEPTH {
+            return Err(self.error(start, "nesting is too deep"));
+        }
+
+        let marker = self.read_bytes(1)?[0];
+
+        let value = match marker {
+            0x00..=0x7F => Json::U64(marker as u64),
+            0x80..=0x8F => self.read_map((marker & 0x0F) as usize, depth)?,
+            0x90..=0x9F => self.read_array((marker & 0x0F) as usize, depth)?,
+            0xA0..=0xBF => self.read_str((marker & 0x1F) as usize)?,
+            0xC0        => Json::Null,
+            0xC2        => Json::Boolean(false),
+            0xC3        => Json::Boolean(true),
+            0xCA        => Json::F64(f32::from_bits(self.read_uint(4)? as u32) as f64),
+            0xCB        => Json::F64(f64::from_bits(self.read_uint(8)?)),
+            0xCC        => Json::U64(self.read_uint(1)?),
+            0xCD        => Json::U64(self.read_uint(2)?),
+            0xCE        => Json::U64(self.read_uint(4)?),
+            0xCF        => Json::U64(self.read_uint(8)?),
+            0xD0        => integer(self.read_uint(1)? as u8 as i8 as i64),
+            0xD1        => integer(self.read_uint(2)? as u16 as i16 as i64),
+            0xD2        => integer(self.read_uint(4)? as u32 as i32 as i64),
+            0xD3        => integer(self.read_uint(8)? as i64),
+            0xD9        => { let length = self.read_length(1)?; self.read_str(length)? },
+            0xDA        => { let length = self.read_length(2)?; self.read_str(length)? },
+            0xDB        => { let length = self.read_length(4)?; self.read_str(length)? },
+            0xDC        => { let length = self.read_length(2)?; self.read_array(length, depth)? },
+            0xDD        => { let length = self.read_length(4)?; self.read_array(length, depth)? },
+            0xDE        => { let length = self.read_length(2)?; self.read_map(length, depth)? },
+            0xDF        => { let length = self.read_length(4)?; self.read_map(length, depth)? },
+            0xE0..=0xFF => Json::I64(marker as i8 as i64),
+            0xC4..=0xC6 => return Err(self.error(start, "binary values are not supported")),
+            0xC7..=0xC9 | 0xD4..=0xD8 => return Err(self.error(start, "extension types are not supported")),
+            _           => return Err(self.error(start, "unknown marker 0xc1")),
+        };
+
+        Ok(value)
+    }
+
+    fn read_str(&mut self, length: usize) -> Result<Json, DecodeError> {
+        let start = self.position;
+        let bytes = self.read_bytes(length)?;
+        match str::from_utf8(bytes) {
+            Ok(text) => Ok(Json::String(String::from(text))),
+            Err(error) => Err(self.error(start + error.valid_up_to(), "string is not utf8-encoded")),
+        }
+    }
+
+    fn read_array(&mut self, length: usize, depth: usize) -> Result<Json, DecodeError> {
+        //  Every element takes at least a byte, which bounds a forged length
+        let mut array = Vec::with_capacity(length.min(self.data.len() - self.position));
+        for _ in 0..length {
+            array.push(self.read_value(depth + 1)?);
+        }
+        Ok(Json::Array(array))
+    }
+
+    fn read_map(&mut self, length: usize, depth: usize) -> Result<Json, DecodeError> {
+        let mut map = BTreeMap::<String, Json>::new();
+        for _ in 0..length {
+            let key_start = self.position;
+            let key = match self.read_value(depth + 1)? {
+                Json::String(key) => key,
+                _                 => return Err(self.error(key_start, "map keys must be strings")),
+            };
+            let value = self.read_value(depth + 1)?;
+            map.insert(key, value);
+        }
+        Ok(Json::Object(map))
+    }
+}
+
+//  The JSON parser only produces I64 for negative numbers, and the
+//  attribute accessors rely on that
+fn integer(value: i64) -> Json {
+    if value >= 0 {
+        Json::U64(value as u64)
+    } else {
+        Json::I64(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::json::ToJson;
+
+    fn round_trip(value: Json) {
+        let encoded = encode(&value).unwrap();
+        assert_eq!(decode(&encoded).unwrap(), value);
+    }
+
+    #[test]
+    fn scalars() {
+        round_trip(Json::Null);
+        round_trip(Json::Boolean(true));
+        round_trip(Json::F64(0.25));
+        round_trip(Json::String(String::from("zażółć")));
+        for &number in &[0, 127, 128, 255, 256, 65535, 65536, 1 << 40, u64::max_value()] {
+            round_trip(Json::U64(number));
+        }
+        for &number in &[-1, -32, -33, -128, -129, -32768, -32769, -(1 << 40), i64::min_value()] {
+            round_trip(Json::I64(number));
+        }
+    }
+
+    #[test]
+    fn long_strings_and_containers() {
+        round_trip(Json::String("x".repeat(40)));
+        round_trip(Json::String("x".repeat(300)));
+        round_trip(Json::String("x".repeat(70000)));
+        round_trip((0..20u64).collect::<Vec<u64>>().to_json());
+        round_trip((0..70000u64).collect::<Vec<u64>>().to_json());
+
+        let mut object = BTreeMap::<String, Json>::new();
+        for key in 0..20 {
+            object.insert(format!("key{}", key), Json::Array(vec![Json::Null]));
+        }
+        round_trip(Json::Object(object));
+    }
+
+    #[test]
+    fn matches_the_json_encoder() {
+        let value = Json::from_str(r#"{"id":1,"objects":[{"x":-2,"name":"a"}],"new_game":true}"#).unwrap();
+        assert_eq!(decode(&encode(&value).unwrap()).unwrap(), value);
+    }
+
+    #[test]
+    fn truncated_data() {
+        let encoded = encode(&Json::String(String::from("truncated"))).unwrap();
+        let error = decode(&encoded[..4]).unwrap_err();
+        assert_eq!(error.offset, 4);
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let error = decode(&[0xC0, 0xC0]).unwrap_err();
+        assert_eq!(error.offset, 1);
+    }
+
+    #[test]
+    fn forged_lengths() {
+        assert!(decode(&[0xDD, 0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+        assert!(decode(&[0xDB, 0xFF, 0xFF, 0xFF, 0xFF, b'a']).is_err());
+    }
+
+    #[test]
+    fn unsupported_values() {
+        assert!(decode(&[0xC1]).is_err());
+        assert!(decode(&[0xC4, 0x01, 0x00]).is_err());
+        assert!(decode(&[0x81, 0x01, 0xC0]).is_err());
+        assert!(decode(&[0xA2, 0xC3, 0x28]).is_err());
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let mut data = vec![0x91; MAX_DEPTH + 2];
+        data.push(0xC0);
+        assert!(decode(&data).is_err());
+
+        let mut data = vec![0x91; MAX_DEPTH];
+        data.push(0xC0);
+        assert!(decode(&data).is_ok());
+    }
+}
@@ -0,0 +1,275 @@
+use types::message::{value_to_string, value_as_f32};
+use visualization::scene::Scene;
+use regex::Regex;
+
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Operator {
+    fn from_name(name: &str) -> Option<Operator> {
+        match name {
+            "==" => Some(Operator::Equal),
+            "!=" => Some(Operator::NotEqual),
+            "<"  => Some(Operator::Less),
+            "<=" => Some(Operator::LessOrEqual),
+            ">"  => Some(Operator::Greater),
+            ">=" => Some(Operator::GreaterOrEqual),
+            _    => None,
+        }
+    }
+
+    fn holds<T: PartialOrd>(&self, lhs: T, rhs: T) -> bool {
+        match *self {
+            Operator::Equal          => lhs == rhs,
+            Operator::NotEqual       => lhs != rhs,
+            Operator::Less           => lhs < rhs,
+            Operator::LessOrEqual    => lhs <= rhs,
+            Operator::Greater        => lhs > rhs,
+            Operator::GreaterOrEqual => lhs >= rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Comparison {
+    attribute: String,
+    operator:  Operator,
+    value:     String,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    //  All the comparisons hold for an object
+    Matches(Vec<Comparison>),
+    Disappears(u32),
+}
+
+//  What a visualization reports when a breakpoint is hit
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub frame:  String,
+    pub object: Option<u32>,
+}
+
+//  Triggers when its condition becomes true, not on every frame it stays true,
+//  so the state of the previous frame is kept
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    text:      String,
+    condition: Condition,
+    matching:  HashSet<u32>,
+}
+
+impl Breakpoint {
+    //  Either "object <id> disappears", or comparisons of attributes joined by "&&",
+    //  like "type == unit && hp < 10"; values are compared as numbers when both are numeric
+    pub fn parse(text: &str) -> Result<Breakpoint, String> {
+        let disappears_re = Regex::new(r"^\s*object\s+(\d+)\s+disappears\s*$").unwrap();
+        let comparison_re = Regex::new(r#"^\s*([^\s=!<>]+)\s*(==|!=|<=|>=|<|>)\s*"?([^"]+?)"?\s*$"#).unwrap();
+
+        let condition = if let Some(captures) = disappears_re.captures(text) {
+            match captures.get(1).unwrap().as_str().parse::<u32>() {
+                Ok(id) => Condition::Disappears(id),
+                Err(_) => return Err(format!("Invalid object id in \"{}\"", text.trim())),
+            }
+        } else {
+            let mut comparisons: Vec<Comparison> = vec![];
+            for part in text.split("&&") {
+                let captures = match comparison_re.captures(part) {
+                    Some(captures) => captures,
+                    None           => return Err(format!("Invalid condition \"{}\", use an attribute, an operator and a value", part.trim())),
+                };
+
+                comparisons.push(Comparison {
+                    attribute: String::from(captures.get(1).unwrap().as_str()),
+                    operator:  Operator::from_name(captures.get(2).unwrap().as_str()).unwrap(),
+                    value:     String::from(captures.get(3).unwrap().as_str()),
+                });
+            }
+            Condition::Matches(comparisons)
+        };
+
+        Ok(Breakpoint {
+            text:      String::from(text.trim()),
+            condition: condition,
+            matching:  HashSet::<u32>::new(),
+        })
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn evaluate(&self, scene: &Scene) -> HashSet<u32> {
+        match self.condition {
+            Condition::Matches(ref comparisons) => scene.objects().iter()
+                .filter(|&(_, object)| comparisons.iter().all(|comparison| {
+                    match object.get(&comparison.attribute) {
+                        Some(value) => match (value_as_f32(value), comparison.value.parse::<f32>()) {
+                            (Some(lhs), Ok(rhs)) => comparison.operator.holds(lhs, rhs),
+                            _                    => comparison.operator.holds(&value_to_string(value), &comparison.value),
+                        },
+                        None        => false,
+                    }
+                }))
+                .map(|(id, _)| *id)
+                .collect(),
+            Condition::Disappears(id) => scene.get(id).into_iter().map(|_| id).collect(),
+        }
+    }
+
+    //  Takes the scene as it is without triggering, for breakpoints added in the middle of a game
+    pub fn reset(&mut self, scene: &Scene) {
+        self.matching = self.evaluate(scene);
+    }
+
+    pub fn check(&mut self, scene: &Scene) -> Option<Hit> {
+        let matching = self.evaluate(scene);
+        let frame = scene.last_message_id().unwrap_or(String::new());
+
+        let hit = match self.condition {
+            Condition::Matches(_)     => matching.difference(&self.matching).min().map(|id| Hit {
+                frame:  frame,
+                object: Some(*id),
+            }),
+            Condition::Disappears(id) => if self.matching.contains(&id) && matching.is_empty() {
+                Some(Hit {
+                    frame:  frame,
+                    object: None,
+                })
+            } else {
+                None
+            },
+        };
+
+        self.matching = matching;
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::message::MessageIn;
+    use visualization::configuration::Configuration;
+    use rustc_serialize::json::Json;
+
+    //  Scenes after each of the keyframes with the given objects, numbered from 1
+    fn scenes(frames: &[&str]) -> Vec<Scene> {
+        let configuration = Configuration::new(String::from("missing.conf"));
+        let mut scene = Scene::new();
+        frames.iter().enumerate()
+            .map(|(index, objects)| {
+                let text = format!(r#"{{"publisher":"p","id":{},"objects":{}}}"#, index + 1, objects);
+                scene.apply(&MessageIn::from_json(Json::from_str(&text).unwrap(), None).unwrap(), &configuration);
+                scene.clone()
+            })
+            .collect()
+    }
+
+    //  Frame ids and objects of the hits, one entry per scene
+    fn hits(text: &str, scenes: &[Scene]) -> Vec<Option<(String, Option<u32>)>> {
+        let mut breakpoint = Breakpoint::parse(text).unwrap();
+        scenes.iter()
+            .map(|scene| breakpoint.check(scene).map(|hit| (hit.frame, hit.object)))
+            .collect()
+    }
+
+    fn hit(frame: &str, object: Option<u32>) -> Option<(String, Option<u32>)> {
+        Some((String::from(frame), object))
+    }
+
+    #[test]
+    fn parses_conditions() {
+        assert_eq!(Breakpoint::parse("  hp < 10 ").unwrap().text(), "hp < 10");
+        assert!(Breakpoint::parse("type == \"unit\" && hp<=10").is_ok());
+        assert!(Breakpoint::parse("object 3 disappears").is_ok());
+        assert!(Breakpoint::parse("hp").is_err());
+        assert!(Breakpoint::parse("hp < 10 &&").is_err());
+        assert!(Breakpoint::parse("object 99999999999 disappears").is_err());
+    }
+
+    #[test]
+    fn compares_numbers_and_strings() {
+        let scenes = scenes(&[
+            r#"[{"id":1,"hp":"9"},{"id":2,"name":"b"}]"#,
+            r#"[{"id":1,"hp":10},{"id":2,"name":"a"}]"#,
+        ]);
+
+        //  As strings "9" > "10", as numbers it's less
+        assert_eq!(hits("hp < 10", &scenes), vec![hit("1", Some(1)), None]);
+        assert_eq!(hits("hp >= 10", &scenes), vec![None, hit("2", Some(1))]);
+        assert_eq!(hits("name < b", &scenes), vec![None, hit("2", Some(2))]);
+        assert_eq!(hits("name != \"a\"", &scenes), vec![hit("1", Some(2)), None]);
+    }
+
+    #[test]
+    fn all_comparisons_have_to_hold() {
+        let scenes = scenes(&[
+            r#"[{"id":1,"type":"unit","hp":20},{"id":2,"type":"tower","hp":5}]"#,
+            r#"[{"id":1,"type":"unit","hp":5},{"id":2,"type":"tower","hp":5}]"#,
+        ]);
+
+        assert_eq!(hits("type == unit && hp < 10", &scenes), vec![None, hit("2", Some(1))]);
+    }
+
+    #[test]
+    fn fires_when_the_condition_becomes_true() {
+        let scenes = scenes(&[
+            r#"[{"id":1,"hp":20}]"#,
+            r#"[{"id":1,"hp":5}]"#,
+            r#"[{"id":1,"hp":4}]"#,
+            r#"[{"id":1,"hp":20}]"#,
+            r#"[{"id":1,"hp":3},{"id":2,"hp":3}]"#,
+            r#"[{"id":1,"hp":3},{"id":2,"hp":3},{"id":3,"hp":3}]"#,
+        ]);
+
+        assert_eq!(hits("hp < 10", &scenes), vec![
+            None,
+            hit("2", Some(1)),
+            None,
+            None,
+            hit("5", Some(1)),
+            hit("6", Some(3)),
+        ]);
+    }
+
+    #[test]
+    fn object_disappears() {
+        let scenes = scenes(&[
+            r#"[{"id":2}]"#,
+            r#"[{"id":1},{"id":2}]"#,
+            r#"[{"id":1}]"#,
+            r#"[{"id":2}]"#,
+            r#"[]"#,
+        ]);
+
+        assert_eq!(hits("object 2 disappears", &scenes), vec![None, None, hit("3", None), None, hit("5", None)]);
+    }
+
+    #[test]
+    fn reset_takes_the_current_state() {
+        let scenes = scenes(&[
+            r#"[{"id":1,"hp":5}]"#,
+            r#"[{"id":1,"hp":5},{"id":2,"hp":5}]"#,
+        ]);
+
+        let mut breakpoint = Breakpoint::parse("hp < 10").unwrap();
+        breakpoint.reset(&scenes[0]);
+        assert!(breakpoint.check(&scenes[0]).is_none());
+        assert_eq!(breakpoint.check(&scenes[1]).and_then(|hit| hit.object), Some(2));
+
+        let mut breakpoint = Breakpoint::parse("object 1 disappears").unwrap();
+        breakpoint.reset(&Scene::new());
+        assert!(breakpoint.check(&Scene::new()).is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use types::message::{MessageIn, Object};
+use types::message::{Object, value_to_string, value_as_f32, value_as_u32};
 use types::{Geometry, ObjectRenderInfo};
 use regex::Regex;
 
@@ -151,67 +151,54 @@ impl Configuration {
         return self.textures.clone();
     }
 
-    pub fn parse_message(&self, msg: &MessageIn) -> (Vec<ObjectRenderInfo>, HashMap<u32, Object>, String) {
-        let empty_str = String::new();
+    pub fn get_render_info(&self, obj: &Object) -> ObjectRenderInfo {
         let default_info = TypeInfo::new();
 
-        let message_id = msg.id.clone();
-
-        let (render_info, details): (Vec<ObjectRenderInfo>, Vec<Option<(u32, Object)>>) = msg.objects.iter()
-            .map(|obj: &Object| -> (ObjectRenderInfo, Option<(u32, Object)>) {
-                let id = obj.get("id").unwrap_or(&empty_str).parse::<u32>().unwrap_or(u32::max_value());
-                let permanent_id = obj.get("permanent_id").unwrap_or(&empty_str).parse::<u32>();
-                let permanent_id = if let Ok(id) = permanent_id { Some(id) } else { None };
-                let x = obj.get("x").unwrap_or(&empty_str).parse::<f32>().unwrap_or(0.0);
-                let y = obj.get("y").unwrap_or(&empty_str).parse::<f32>().unwrap_or(0.0);
-                let z = obj.get("z").unwrap_or(&empty_str).parse::<f32>().unwrap_or(0.0);
-
-                let type_info = match obj.get("type") {
-                    Some(type_name) => self.types.get(type_name),
-                    None            => None,
-                }.unwrap_or(&default_info);
-
-                let (r, g, b) = type_info.color;
-
-                let info = ObjectRenderInfo {
-                    id:            id,
-                    permanent_id: permanent_id,
-                    model:         type_info.model.clone(),
-                    texture_name:  type_info.texture.clone(),
-                    color:         (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0),
-                    position:      (x, y, z),
-                };
-
-                let details_option = if id == u32::max_value() { None } else { Some((id, obj.clone())) };
-
-                (info, details_option)
-            })
-            .unzip();
-
-        let objects_vec: Vec<(u32, Object)> = details.into_iter()
-            .filter(|option| option.is_some())
-            .map(|option| option.unwrap())
-            .collect();
-
-        let mut objects = HashMap::<u32, Object>::new();
-        for (id, object) in objects_vec {
-            objects.insert(id, object);
+        let id = obj.get("id").and_then(value_as_u32).unwrap_or(u32::max_value());
+        let permanent_id = obj.get("permanent_id").and_then(value_as_u32);
+        let x = obj.get("x").and_then(value_as_f32).unwrap_or(0.0);
+        let y = obj.get("y").and_then(value_as_f32).unwrap_or(0.0);
+        let z = obj.get("z").and_then(value_as_f32).unwrap_or(0.0);
+
+        let type_info = match obj.get("type") {
+            Some(type_name) => self.types.get(&value_to_string(type_name)),
+            None            => None,
+        }.unwrap_or(&default_info);
+
+        let (r, g, b) = type_info.color;
+
+        ObjectRenderInfo {
+            id:            id,
+            permanent_id: permanent_id,
+            model:         type_info.model.clone(),
+            texture_name:  type_info.texture.clone(),
+            color:         (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0),
+            position:      (x, y, z),
         }
+    }
 
-        (render_info, objects, message_id)
+    pub fn get_key_name(&self, code: VirtualKeyCode) -> Option<String> {
+        self.key_map.get(&code).cloned()
     }
 
-    pub fn get_key_str(&self, code: VirtualKeyCode, attributes: &Object) -> Option<String> {
-        let key_name = self.key_map.get(&code)
-            .expect("Unknown key pressed");
+    //  Listed in the "key" rule of any type, such keys are left to the publisher
+    pub fn binds_key(&self, code: VirtualKeyCode) -> bool {
+        match self.key_map.get(&code) {
+            Some(key_name) => self.types.values().any(|info| info.keys.contains(key_name)),
+            None           => false,
+        }
+    }
+
+    //  Keys listed in the "key" rule of the object type are addressed to its objects
+    pub fn handles_key(&self, key_name: &str, attributes: &Object) -> bool {
         let type_info = match attributes.get("type") {
             None       => None,
-            Some(info) => self.types.get(info),
+            Some(info) => self.types.get(&value_to_string(info)),
         };
 
         match type_info {
-            None       => None,
-            Some(info) => if info.keys.contains(key_name) { Some(key_name.clone()) } else { None },
+            None       => false,
+            Some(info) => info.keys.contains(key_name),
         }
     }
 }
@@ -1,35 +1,108 @@
-use types::message::{MessageIn, MessageOut, Object};
-use types::ObjectRenderInfo;
+use types::message::{MessageIn, MessageOut, EventKind, Modifiers, Object, value_to_string};
 use types::double_channel::Endpoint;
 use visualization::camera::Camera;
 use visualization::configuration::Configuration;
 use visualization::render::Renderer;
+use visualization::history::History;
+use visualization::breakpoint::{Breakpoint, Hit};
 
 use glutin;
 use glutin::ElementState;
+use glutin::VirtualKeyCode as Key;
 use gl;
 use cgmath;
+use cgmath::{Matrix4, Point2, Vector4, SquareMatrix};
 
+use std::time::{Duration, Instant};
 use std::collections::HashMap;
-use std::time::{Instant};
 use std::f64::consts::PI;
 use std::cmp::max;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FramePolicy {
+    //  Only the newest frame is rendered, the ones in between are applied but never shown
+    Latest,
+    //  Every frame is rendered, one per window refresh
+    Queue,
+    //  Like queue, but the publisher waits for the viewer to ask for every next frame
+    Lockstep,
+}
+
+impl FramePolicy {
+    pub fn from_name(name: &str) -> Option<FramePolicy> {
+        match name {
+            "latest"   => Some(FramePolicy::Latest),
+            "queue"    => Some(FramePolicy::Queue),
+            "lockstep" => Some(FramePolicy::Lockstep),
+            _          => None,
+        }
+    }
+}
+
+//  What the core sends to a visualization
+pub enum Command {
+    Frame(MessageIn),
+    //  Shows the frame with the given id, if it's still in the history
+    Goto(String),
+    //  Notes of the bookmarked frames by their ids, replacing the previous ones
+    Bookmarks(HashMap<String, String>),
+    //  Breakpoints by their numbers, replacing the previous ones
+    Breakpoints(Vec<(usize, Breakpoint)>),
+    Close,
+}
+
+//  What a visualization sends back to the core
+pub enum Report {
+    Event(MessageOut),
+    BreakpointHit(usize, Hit),
+    Closed,
+}
+
+const STEP_KEY: Key = Key::Return;
+//  In latest mode, so a fast publisher can't keep the window from being redrawn
+const MAX_FRAMES_PER_REFRESH: u64 = 100;
+const HISTORY_LENGTH: usize = 1000;
+const TIMELINE_WIDTH: usize = 40;
+
 pub struct Visualization {
-    link_core:     Endpoint<Option<MessageOut>, Option<MessageIn>>,
+    link_core:     Endpoint<Report, Command>,
     publisher:     String,
     configuration: Configuration,
+    policy:        FramePolicy,
+    stale_after:   Duration,
 }
 
 impl Visualization {
-    pub fn new(link: Endpoint<Option<MessageOut>, Option<MessageIn>>, publisher: String, config_file: String) -> Visualization {
+    pub fn new(link: Endpoint<Report, Command>, publisher: String, config_file: String, policy: FramePolicy, stale_after: Duration) -> Visualization {
         Visualization {
             link_core:     link,
             publisher:     publisher,
             configuration: Configuration::new(config_file),
+            policy:        policy,
+            stale_after:   stale_after,
         }
     }
 
+    //  An event of the given kind with nothing filled in but the common fields
+    fn event(&self, kind: EventKind, frame_id: String, modifiers: Modifiers) -> MessageOut {
+        MessageOut {
+            publisher: self.publisher.clone(),
+            id:        frame_id,
+            kind:      kind,
+            object_id: None,
+            key_code:  None,
+            button:    None,
+            x:         None,
+            y:         None,
+            scroll:    None,
+            modifiers: modifiers,
+        }
+    }
+
+    fn send_event(&self, msg: MessageOut) {
+        let _ = self.link_core.send(Report::Event(msg));
+    }
+
     pub fn run(&mut self) {
         let window_name = format!("[{}]", self.publisher);
         let (mut window_x, mut window_y) = (800, 600);
@@ -57,10 +130,13 @@ impl Visualization {
 
         let mut active_object: Option<u32> = None;
 
-        let mut last_message_id: Option<String> = None;
-        let mut objects: HashMap<u32, Object> = HashMap::<u32, Object>::new();
-        let mut render_info: Vec<ObjectRenderInfo> = vec![];
-        let mut permanent_info: HashMap<u32, ObjectRenderInfo> = HashMap::<u32, ObjectRenderInfo>::new();
+        let mut history = History::new(HISTORY_LENGTH);
+        let mut bookmarks = HashMap::<String, String>::new();
+        let mut breakpoints: Vec<(usize, Breakpoint)> = vec![];
+        let mut notice: Option<String> = None;
+        let mut dropped_frames: u64 = 0;
+        let mut step_requested = false;
+        let mut waiting_since = Instant::now();
 
         let mut mouse_x = 0;
         let mut mouse_y = 0;
@@ -70,26 +146,66 @@ impl Visualization {
         let mut is_right_pressed  = false;
         let mut is_middle_pressed = false;
 
+        let mut modifiers = Modifiers::default();
+
         'main: loop {
-            if let Ok(msg_option) = self.link_core.try_recv() {
-                match msg_option {
-                    Some(msg) => {
-                        let parsed_message = self.configuration.parse_message(&msg);
-                        render_info     = parsed_message.0;
-                        objects         = parsed_message.1;
-                        last_message_id = Some(parsed_message.2);
+            let mut applied_frames = 0;
+            while let Ok(command) = self.link_core.try_recv() {
+                match command {
+                    Command::Frame(msg) => {
+                        history.push(&msg, &self.configuration);
+                        applied_frames += 1;
+                        step_requested = false;
+                        waiting_since = Instant::now();
+
+                        //  Every breakpoint follows the frames, the first one hit pauses on the newest frame
+                        //  and selects its object; the frames after a hit wait in the history. While
+                        //  already paused the hits are only reported, so the shown frame stays.
+                        let hits: Vec<(usize, Hit)> = breakpoints.iter_mut()
+                            .filter_map(|&mut (number, ref mut breakpoint)| breakpoint.check(history.live()).map(|hit| (number, hit)))
+                            .collect();
+                        if let Some(&(_, ref hit)) = hits.first() {
+                            if !history.is_paused() {
+                                history.pause(&self.configuration);
+                                if hit.object.is_some() {
+                                    active_object = hit.object;
+                                }
+                            }
+                        }
+                        if !hits.is_empty() {
+                            for (number, hit) in hits {
+                                let _ = self.link_core.send(Report::BreakpointHit(number, hit));
+                            }
+                            break;
+                        }
+
+                        if self.policy != FramePolicy::Latest || applied_frames >= MAX_FRAMES_PER_REFRESH {
+                            break;
+                        }
+                    },
+                    Command::Goto(frame_id) => {
+                        notice = if history.goto(&frame_id, &self.configuration) {
+                            None
+                        } else {
+                            Some(format!("Frame {} is not in the history", frame_id))
+                        };
                     },
-                    None      => {
+                    Command::Bookmarks(notes) => bookmarks = notes,
+                    Command::Breakpoints(list) => {
+                        breakpoints = list;
+                        for &mut (_, ref mut breakpoint) in breakpoints.iter_mut() {
+                            breakpoint.reset(history.live());
+                        }
+                    },
+                    Command::Close => {
                         println!("(visualization) terminating");
                         break 'main;
                     },
                 };
             }
-
-            for object in render_info.clone() {
-                if let Some(id) = object.permanent_id { let _ = permanent_info.insert(id, object.clone()); }
+            if applied_frames > 1 {
+                dropped_frames += applied_frames - 1;
             }
-            render_info = render_info.into_iter().filter(|object| object.permanent_id.is_none() ).collect();
 
             let time_now = Instant::now();
             let time_from_start = time_now - time_start;
@@ -108,6 +224,17 @@ impl Visualization {
                     },
 
                     MouseWheel(glutin::MouseScrollDelta::LineDelta(_, y), _) => {
+                        if let Some(frame_id) = history.current().last_message_id() {
+                            let position = world_position(&camera, mouse_pos, (window_x, window_y));
+                            self.send_event(MessageOut {
+                                object_id: renderer.get_id((mouse_x as usize, mouse_y as usize)),
+                                x:         position.map(|position| position.x),
+                                y:         position.map(|position| position.y),
+                                scroll:    Some(y),
+                                ..self.event(EventKind::Scroll, frame_id, modifiers)
+                            });
+                        }
+
                         camera.zoom(y);
                     },
                     MouseMoved(x, y) => {
@@ -137,33 +264,98 @@ impl Visualization {
                             _      => {},
                         };
 
-                        if state == Pressed && button == Left {
+                        if state == Pressed {
                             let id = renderer.get_id((mouse_x as usize, mouse_y as usize));
-                            active_object = id;
+                            if button == Left {
+                                active_object = id;
+                            }
+
+                            let button_name = match button {
+                                Left     => Some("left"),
+                                Right    => Some("right"),
+                                Middle   => Some("middle"),
+                                Other(_) => None,
+                            };
+
+                            if let (Some(name), Some(frame_id)) = (button_name, history.current().last_message_id()) {
+                                let position = world_position(&camera, mouse_pos, (window_x, window_y));
+                                self.send_event(MessageOut {
+                                    object_id: id,
+                                    button:    Some(String::from(name)),
+                                    x:         position.map(|position| position.x),
+                                    y:         position.map(|position| position.y),
+                                    ..self.event(EventKind::MouseClick, frame_id, modifiers)
+                                });
+                            }
                         }
                     },
 
-                    KeyboardInput(ElementState::Pressed, _, Some(code)) => {
-                        if let Some(object_id) = active_object {
-                            match objects.get(&object_id) {
-                                None             => {},
-                                Some(attributes) => {
-                                    let maybe_code_str = self.configuration.get_key_str(code, attributes);
-
-                                    if let Some(code_str) = maybe_code_str {
-                                        if let Some(id) = last_message_id.clone() {
-                                            let _ = self.link_core.send(Some(
-                                                MessageOut {
-                                                    publisher: self.publisher.clone(),
-                                                    id:        id,
-                                                    object_id: object_id,
-                                                    key_code:  code_str,
-                                                }
-                                            ));
-                                        }
-                                    }
-                                }
+                    KeyboardInput(state, _, Some(code)) => {
+                        let pressed = state == ElementState::Pressed;
+
+                        //  The step key isn't passed on as a key event in lockstep mode
+                        if self.policy == FramePolicy::Lockstep && code == STEP_KEY {
+                            if let (true, false, Some(frame_id)) = (pressed, step_requested, history.live().last_message_id()) {
+                                self.send_event(self.event(EventKind::NextFrame, frame_id, modifiers));
+                                step_requested = true;
+                                waiting_since = Instant::now();
                             }
+                            continue;
+                        }
+
+                        //  So are the keys browsing the history and its bookmarks, unless the configuration binds them
+                        let bound = self.configuration.binds_key(code);
+                        let browse: Option<fn(&mut History, &Configuration)> = match code {
+                            _ if bound  => None,
+                            Key::Space  => Some(History::toggle_pause),
+                            Key::Comma  => Some(History::step_back),
+                            Key::Period => Some(History::step_forward),
+                            Key::Home   => Some(History::first),
+                            Key::End    => Some(History::last),
+                            _           => None,
+                        };
+                        if let Some(browse) = browse {
+                            if pressed {
+                                browse(&mut history, &self.configuration);
+                                notice = None;
+                            }
+                            continue;
+                        }
+
+                        let seek = match code {
+                            _ if bound    => None,
+                            Key::LBracket => Some(false),
+                            Key::RBracket => Some(true),
+                            _             => None,
+                        };
+                        if let Some(forward) = seek {
+                            if pressed && !history.seek(forward, |id| bookmarks.contains_key(id), &self.configuration) {
+                                notice = Some(String::from("No more bookmarks in this direction"));
+                            }
+                            continue;
+                        }
+
+                        match code {
+                            Key::LShift | Key::RShift                       => modifiers.shift = pressed,
+                            Key::LControl | Key::RControl                   => modifiers.ctrl = pressed,
+                            Key::LAlt | Key::RAlt | Key::LMenu | Key::RMenu => modifiers.alt = pressed,
+                            _                                               => {},
+                        }
+
+                        let scene = history.current();
+                        if let (Some(key_name), Some(frame_id)) = (self.configuration.get_key_name(code), scene.last_message_id()) {
+                            //  Other keys aren't addressed to any object
+                            let object_id = match active_object.and_then(|id| scene.get(id).map(|attributes| (id, attributes))) {
+                                Some((id, attributes)) if self.configuration.handles_key(&key_name, attributes) => Some(id),
+                                _                                                                             => None,
+                            };
+                            let kind = if pressed { EventKind::KeyPressed } else { EventKind::KeyReleased };
+
+                            self.send_event(MessageOut {
+                                object_id: object_id,
+                                key_code:  Some(key_name),
+                                ..self.event(kind, frame_id, modifiers)
+                            });
                         }
                     },
 
@@ -173,34 +365,83 @@ impl Visualization {
 
             //  Calculate uniform tranformations
             let (window_x, window_y) = window.get_inner_size().unwrap();
-            let aspect_ratio = (window_x as f32) / (window_y as f32);
-
-            let camera_transformation = camera.get_matrix();
-            let proj = cgmath::perspective(cgmath::Deg(50.0f32), aspect_ratio, 0.01, 1000.0);
-            let camera_projection = (proj * camera_transformation).into();
+            let camera_projection = view_projection(&camera, (window_x, window_y)).into();
 
             let phi = (time_from_start.as_secs() as f64 + ((time_from_start.subsec_nanos() as f64) / 1000000000.0)) % (2.0 * PI);
+            let scene = history.current();
             let mut strings: Vec<String> = vec![];
             if let Some(id) = active_object {
-                if let Some(object) = objects.get(&id) {
+                if let Some(object) = scene.get(id) {
                     strings = sort_stats(&object);
                 }
             }
-            renderer.render(&render_info, &permanent_info, camera_projection, active_object, strings, phi);
+            let mut status = vec![history.timeline(TIMELINE_WIDTH, &bookmarks)];
+            if let Some(note) = scene.frame_id().and_then(|id| bookmarks.get(id)) {
+                status.push(format!("Bookmark: {}", note));
+            }
+            status.extend(notice.clone());
+            status.extend(match self.policy {
+                FramePolicy::Latest                     => vec![format!("Dropped frames: {}", dropped_frames)],
+                FramePolicy::Queue                      => vec![],
+                FramePolicy::Lockstep if step_requested => vec![String::from("Lockstep: waiting for the next frame")],
+                FramePolicy::Lockstep                   => vec![String::from("Lockstep: press Return for the next frame")],
+            });
+
+            //  In lockstep mode the publisher is expected to wait until it's asked for a frame
+            let waiting_for_frame = self.policy != FramePolicy::Lockstep || step_requested || history.live().last_message_id().is_none();
+            let silence = time_now.duration_since(waiting_since);
+            if waiting_for_frame && silence >= self.stale_after {
+                status.push(format!("Stale: no frame for {} s", silence.as_secs()));
+            }
+
+            renderer.render(scene.render_info(), scene.anonymous_info(), scene.permanent_info(), camera_projection, active_object, strings, status, phi);
 
             window.swap_buffers()
                 .expect("Failed to swap buffers");
         }
 
-        let _ = self.link_core.send(None);
+        let _ = self.link_core.send(Report::Closed);
     }
 }
 
+fn view_projection(camera: &Camera, (window_x, window_y): (u32, u32)) -> Matrix4<f32> {
+    let aspect_ratio = (window_x as f32) / (window_y as f32);
+    let proj = cgmath::perspective(cgmath::Deg(50.0f32), aspect_ratio, 0.01, 1000.0);
+
+    proj * camera.get_matrix()
+}
+
+//  Casts a ray from the cursor onto the z = 0 plane, where the objects without "z" lie
+fn world_position(camera: &Camera, mouse_pos: Point2<f32>, window: (u32, u32)) -> Option<Point2<f32>> {
+    let inverse = match view_projection(camera, window).invert() {
+        Some(inverse) => inverse,
+        None          => return None,
+    };
+
+    let ndc_x = 2.0 * mouse_pos.x / (window.0 as f32) - 1.0;
+    let ndc_y = 2.0 * mouse_pos.y / (window.1 as f32) - 1.0;
+    let unproject = |depth: f32| {
+        let point = inverse * Vector4::new(ndc_x, ndc_y, depth, 1.0);
+        point.truncate() / point.w
+    };
+
+    let near = unproject(-1.0);
+    let direction = unproject(1.0) - near;
+    if direction.z.abs() < 1e-6 || near.z * direction.z > 0.0 {
+        return None;
+    }
+
+    let point = near + direction * (-near.z / direction.z);
+    Some(Point2::new(point.x, point.y))
+}
+
 fn sort_stats(object: &Object) -> Vec<String> {
     use std::cmp::Ordering::*;
     use std::ops::Deref;
 
-    let mut stats: Vec<(&String, &String)> = object.iter().collect();
+    let mut stats: Vec<(&String, String)> = object.iter()
+        .map(|(attribute, value)| (attribute, value_to_string(value)))
+        .collect();
     stats.sort_by(|lhs, rhs| {
         match (lhs.0.as_str(), rhs.0.as_str()) {
             ("id", _)   => Less,
@@ -215,6 +456,6 @@ fn sort_stats(object: &Object) -> Vec<String> {
     for &(attribute, _) in &stats { longest = max(longest, attribute.len()); }
 
     stats.iter()
-        .map(|&(attribute, value)| format!("{:len$}: {}", attribute.clone(), value, len = longest) )
+        .map(|&(attribute, ref value)| format!("{:len$}: {}", attribute.clone(), value, len = longest) )
         .collect()
 }
@@ -0,0 +1,361 @@
+use types::message::MessageIn;
+use visualization::configuration::Configuration;
+use visualization::scene::Scene;
+
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+
+//  A copy of the scene is kept once every this many frames
+const CHECKPOINT_INTERVAL: usize = 50;
+
+//  The last received frames. Only every CHECKPOINT_INTERVAL-th scene is kept, the shown
+//  one is rebuilt from the nearest of them. Frames keep being applied while paused,
+//  so resuming continues from the newest one, but at most twice the capacity is kept.
+pub struct History {
+    live:        Scene,
+    frames:      VecDeque<MessageIn>,
+    //  Frames are numbered from the first one received, this is the number of the oldest kept
+    first:       usize,
+    //  Scenes after the frames with the given numbers, the oldest one is always at the first frame
+    checkpoints: VecDeque<(usize, Scene)>,
+    capacity:    usize,
+    //  Number of the frame shown while paused with its scene, the live scene is shown otherwise
+    shown:       Option<(usize, Scene)>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            live:        Scene::new(),
+            frames:      VecDeque::<MessageIn>::new(),
+            first:       0,
+            checkpoints: VecDeque::<(usize, Scene)>::new(),
+            capacity:    capacity,
+            shown:       None,
+        }
+    }
+
+    pub fn push(&mut self, msg: &MessageIn, configuration: &Configuration) {
+        self.live.apply(msg, configuration);
+
+        let number = self.first + self.frames.len();
+        self.frames.push_back(msg.clone());
+        if number % CHECKPOINT_INTERVAL == 0 || self.checkpoints.is_empty() {
+            self.checkpoints.push_back((number, self.live.clone()));
+        }
+
+        //  Frames go by whole checkpoints as long as at least capacity frames remain. The shown
+        //  one stays while paused, with the new frames buffered up to twice the capacity
+        while self.checkpoints.len() > 1 {
+            let next = self.checkpoints[1].0;
+            let remaining = self.frames.len() - (next - self.first);
+            let keeps_shown = self.shown.as_ref().map_or(false, |&(number, _)| number < next);
+            if remaining < self.capacity || (keeps_shown && self.frames.len() <= 2 * self.capacity) {
+                break;
+            }
+
+            self.frames.drain(..(next - self.first));
+            self.checkpoints.pop_front();
+            self.first = next;
+        }
+
+        //  Past that the view moves on to the oldest kept frame
+        if let Some(number) = self.position() {
+            if number < self.first {
+                let first = self.first;
+                self.show(first, configuration);
+            }
+        }
+    }
+
+    //  The scene to render and to pick objects from
+    pub fn current(&self) -> &Scene {
+        match self.shown {
+            Some((_, ref scene)) => scene,
+            None                 => &self.live,
+        }
+    }
+
+    pub fn live(&self) -> &Scene {
+        &self.live
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.shown.is_some()
+    }
+
+    fn position(&self) -> Option<usize> {
+        self.shown.as_ref().map(|&(number, _)| number)
+    }
+
+    fn newest(&self) -> usize {
+        self.first + self.frames.len() - 1
+    }
+
+    //  Pauses on the frame with the given number, which has to be kept
+    fn show(&mut self, number: usize, configuration: &Configuration) {
+        if self.position() == Some(number) {
+            return;
+        }
+
+        let scene = if number == self.newest() {
+            self.live.clone()
+        } else {
+            let &(checkpoint, ref scene) = self.checkpoints.iter()
+                .rev()
+                .find(|&&(checkpoint, _)| checkpoint <= number)
+                .expect("The oldest frame has no checkpoint");
+
+            let mut scene = scene.clone();
+            for msg in self.frames.iter().skip(checkpoint + 1 - self.first).take(number - checkpoint) {
+                scene.apply(msg, configuration);
+            }
+            scene
+        };
+
+        self.shown = Some((number, scene));
+    }
+
+    pub fn toggle_pause(&mut self, configuration: &Configuration) {
+        if self.is_paused() {
+            self.shown = None;
+        } else {
+            self.pause(configuration);
+        }
+    }
+
+    //  On the newest frame, unless already paused
+    pub fn pause(&mut self, configuration: &Configuration) {
+        if self.shown.is_none() && !self.frames.is_empty() {
+            let newest = self.newest();
+            self.show(newest, configuration);
+        }
+    }
+
+    pub fn step_back(&mut self, configuration: &Configuration) {
+        self.pause(configuration);
+        if let Some(number) = self.position() {
+            let first = self.first;
+            self.show(if number > first { number - 1 } else { first }, configuration);
+        }
+    }
+
+    //  Stays paused on the newest frame
+    pub fn step_forward(&mut self, configuration: &Configuration) {
+        if let Some(number) = self.position() {
+            let newest = self.newest();
+            self.show(min(number + 1, newest), configuration);
+        }
+    }
+
+    pub fn first(&mut self, configuration: &Configuration) {
+        if !self.frames.is_empty() {
+            let first = self.first;
+            self.show(first, configuration);
+        }
+    }
+
+    pub fn last(&mut self, _configuration: &Configuration) {
+        self.shown = None;
+    }
+
+    //  Pauses on the newest frame with the id, false if it's no longer kept
+    pub fn goto(&mut self, frame_id: &str, configuration: &Configuration) -> bool {
+        let found = self.frames.iter().rposition(|msg| msg.id == frame_id);
+        if let Some(index) = found {
+            let number = self.first + index;
+            self.show(number, configuration);
+        }
+        found.is_some()
+    }
+
+    //  Pauses on the nearest frame before or after the shown one whose id is accepted
+    pub fn seek<F: Fn(&str) -> bool>(&mut self, forward: bool, accept: F, configuration: &Configuration) -> bool {
+        let count = self.frames.len();
+        if count == 0 {
+            return false;
+        }
+
+        let current = self.position().map_or(count - 1, |number| number - self.first);
+        let found = {
+            let accepted = |index: &usize| accept(&self.frames[*index].id);
+            if forward {
+                (current + 1..count).find(accepted)
+            } else {
+                (0..current).rev().find(accepted)
+            }
+        };
+
+        if let Some(index) = found {
+            let number = self.first + index;
+            self.show(number, configuration);
+        }
+        found.is_some()
+    }
+
+    //  A bar with the shown frame and the bookmarks marked, like "|-*-#------| 4/11 paused"
+    pub fn timeline(&self, width: usize, bookmarks: &HashMap<String, String>) -> String {
+        let count = self.frames.len();
+        if count == 0 {
+            return String::from("No frames yet");
+        }
+
+        let column = |index: usize| if count > 1 { index * (width - 1) / (count - 1) } else { width - 1 };
+        let mut bar = vec!['-'; width];
+        if !bookmarks.is_empty() {
+            for (index, msg) in self.frames.iter().enumerate() {
+                if bookmarks.contains_key(&msg.id) {
+                    bar[column(index)] = '*';
+                }
+            }
+        }
+
+        let index = self.position().map_or(count - 1, |number| number - self.first);
+        bar[column(index)] = '#';
+        let bar: String = bar.into_iter().collect();
+
+        format!("|{}| {}/{}{}", bar, index + 1, count, if self.is_paused() { " paused" } else { "" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use types::message::{FrameKind, Object};
+    use rustc_serialize::json::Json;
+
+    const CAPACITY: usize = 100;
+
+    fn configuration() -> Configuration {
+        Configuration::new(String::from("missing.conf"))
+    }
+
+    //  Moves one object every frame, adds or updates another and removes some now and then
+    fn frame(number: usize) -> MessageIn {
+        let mut moving = Object::new();
+        moving.insert(String::from("id"), Json::U64(1));
+        moving.insert(String::from("x"), Json::U64(number as u64));
+
+        let mut other = Object::new();
+        other.insert(String::from("id"), Json::U64((number % 5 + 2) as u64));
+        other.insert(String::from("step"), Json::U64(number as u64));
+
+        MessageIn {
+            publisher:         String::from("p"),
+            id:                number.to_string(),
+            kind:              if number == 0 { FrameKind::Keyframe } else { FrameKind::Delta },
+            objects:           vec![moving, other],
+            removed:           if number % 3 == 0 { vec![((number + 1) % 5 + 2) as u32] } else { vec![] },
+            removed_permanent: vec![],
+            clear_permanent:   false,
+            new_game:          false,
+        }
+    }
+
+    fn history(count: usize, configuration: &Configuration) -> History {
+        let mut history = History::new(CAPACITY);
+        for number in 0..count {
+            history.push(&frame(number), configuration);
+        }
+        history
+    }
+
+    //  The scene after the given frame, built from the very first one
+    fn expected(number: usize, configuration: &Configuration) -> Scene {
+        let mut scene = Scene::new();
+        for number in 0..number + 1 {
+            scene.apply(&frame(number), configuration);
+        }
+        scene
+    }
+
+    fn assert_shows(history: &History, number: usize, configuration: &Configuration) {
+        let expected = expected(number, configuration);
+        assert_eq!(history.position(), Some(number));
+        assert_eq!(history.current().frame_id(), expected.frame_id());
+        assert_eq!(history.current().objects(), expected.objects());
+    }
+
+    #[test]
+    fn evicts_whole_checkpoints() {
+        let configuration = configuration();
+        let mut history = History::new(CAPACITY);
+        for number in 0..500 {
+            history.push(&frame(number), &configuration);
+
+            assert_eq!(history.first % CHECKPOINT_INTERVAL, 0);
+            assert_eq!(history.checkpoints[0].0, history.first);
+            assert_eq!(history.newest(), number);
+            if number >= CAPACITY {
+                assert!(history.frames.len() >= CAPACITY);
+                assert!(history.frames.len() < CAPACITY + CHECKPOINT_INTERVAL);
+            }
+        }
+        assert_eq!(history.first, 400);
+        assert_eq!(history.checkpoints.len(), 2);
+    }
+
+    #[test]
+    fn shown_frame_survives_eviction() {
+        let configuration = configuration();
+        let mut history = history(120, &configuration);
+        assert!(history.goto("10", &configuration));
+
+        for number in 120..200 {
+            history.push(&frame(number), &configuration);
+        }
+        assert_eq!(history.first, 0);
+        assert_shows(&history, 10, &configuration);
+
+        //  Past twice the capacity the view moves on
+        for number in 200..260 {
+            history.push(&frame(number), &configuration);
+            assert!(history.frames.len() <= 2 * CAPACITY);
+        }
+        assert!(history.first > 10);
+        let first = history.first;
+        assert_shows(&history, first, &configuration);
+
+        history.toggle_pause(&configuration);
+        assert!(!history.is_paused());
+        assert_eq!(history.current().objects(), expected(259, &configuration).objects());
+    }
+
+    #[test]
+    fn navigation_rebuilds_the_scenes() {
+        let configuration = configuration();
+        let mut history = history(230, &configuration);
+        let first = history.first;
+        assert_eq!(first, 100);
+
+        history.step_back(&configuration);
+        assert_shows(&history, 228, &configuration);
+        history.step_forward(&configuration);
+        assert_shows(&history, 229, &configuration);
+        history.step_forward(&configuration);
+        assert_shows(&history, 229, &configuration);
+
+        history.first(&configuration);
+        assert_shows(&history, first, &configuration);
+        history.step_back(&configuration);
+        assert_shows(&history, first, &configuration);
+        history.step_forward(&configuration);
+        assert_shows(&history, first + 1, &configuration);
+
+        assert!(history.goto("173", &configuration));
+        assert_shows(&history, 173, &configuration);
+        assert!(!history.goto("99", &configuration));
+        assert_shows(&history, 173, &configuration);
+
+        assert!(history.seek(true, |id| id.ends_with('0'), &configuration));
+        assert_shows(&history, 180, &configuration);
+        assert!(history.seek(false, |id| id.ends_with('7'), &configuration));
+        assert_shows(&history, 177, &configuration);
+        assert!(!history.seek(false, |id| id == "99", &configuration));
+        assert_shows(&history, 177, &configuration);
+
+        history.last(&configuration);
+        assert!(!history.is_paused());
+        assert_eq!(history.current().objects(), expected(229, &configuration).objects());
+    }
+}
@@ -1,4 +1,7 @@
+pub mod breakpoint;
 pub mod camera;
 pub mod configuration;
 pub mod core;
+pub mod history;
 pub mod render;
+pub mod scene;
@@ -8,11 +8,14 @@ use std::ptr;
 use std::str;
 use std::ffi::CString;
 use std::mem;
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
 use std::f64::consts::PI;
 
 use types::{Geometry, ObjectRenderInfo};
 
+const FONT_HEIGHT: f32 = 16.0;
+const FONT_WIDTH: f32 = 8.0;
+
 const SQUARE_VERTICES: &'static [GLfloat] = &[
     -0.5, -0.5, -0.5, 0.0, 0.0,
     -0.5,  0.5, -0.5, 0.0, 1.0,
@@ -95,6 +98,12 @@ const PYRAMID_INDICES: &'static [GLuint] = &[
     3, 0, 4,
 ];
 
+struct GlyphUniforms {
+    transform: GLint,
+    tex_u:     GLint,
+    tex_v:     GLint,
+}
+
 pub struct Renderer {
     x: usize,
     y: usize,
@@ -219,11 +228,13 @@ impl Renderer {
 
     pub fn render(
             &mut self,
-            objects: &Vec<ObjectRenderInfo>,
-            persistent: &HashMap<u32, ObjectRenderInfo>,
+            objects: &BTreeMap<u32, ObjectRenderInfo>,
+            anonymous: &Vec<ObjectRenderInfo>,
+            persistent: &BTreeMap<u32, ObjectRenderInfo>,
             camera_projection: cgmath::Matrix4<f32>,
             active_object: Option<u32>,
             strings: Vec<String>,
+            status: Vec<String>,
             phi: f64) {
 
         let framebuffer = self.framebuffer
@@ -394,7 +405,11 @@ impl Renderer {
                     }
                 };
 
-                for object in objects {
+                //  In the order of their ids, so overlapping objects are always drawn the same way
+                for (_, object) in objects {
+                    draw_object(object);
+                };
+                for object in anonymous {
                     draw_object(object);
                 };
                 for (_, object) in persistent {
@@ -410,11 +425,8 @@ impl Renderer {
 
         check_gl_error("rendering to framebuffer");
 
-        //  Draw strings
+        //  Draw strings, the stats in the top left corner and the status in the bottom left one
         unsafe {
-            const FONT_HEIGHT: f32 = 16.0;
-            const FONT_WIDTH: f32 = 8.0;
-            let screen_width = self.x as f32;
             let screen_height = self.y as f32;
 
             gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer.unwrap());
@@ -482,42 +494,13 @@ impl Renderer {
                     mem::transmute(3 * mem::size_of::<GLfloat>())
                 );
 
-                let mut x_pos = 0.0;
-                let mut y_pos = screen_height;
-                for text in strings {
-                    //  New Line
-                    y_pos -= FONT_HEIGHT;
-                    x_pos = 0.0;
-
-                    let ascii = string_to_renderable(text);
-                    for byte in ascii {
-                        if byte == 10 { /* /n */
-                            y_pos -= FONT_HEIGHT;
-                            x_pos = 0.0;
-                        } else if byte == 9 { /* /t */
-                            x_pos += 4.0;
-                        } else {
-                            let (tex_u, tex_v) = get_glyph_position(byte);
-                            gl::Uniform1f(tex_u_uniform_loc, tex_u);
-                            gl::Uniform1f(tex_v_uniform_loc, tex_v);
-
-                            let transform = cgmath::Matrix3::<f32>::new(
-                                2.0 * FONT_WIDTH / screen_width, 0.0, 0.0,
-                                0.0, 2.0 * FONT_HEIGHT / screen_height, 0.0,
-                                (2.0 * x_pos + FONT_WIDTH - screen_width) / screen_width, (2.0 * y_pos + FONT_HEIGHT - screen_height) / screen_height, 1.0
-                            );
-                            gl::UniformMatrix3fv(
-                                transform_uniform_loc,
-                                1,
-                                gl::FALSE,
-                                mem::transmute(&transform)
-                            );
-
-                            gl::DrawElements(gl::TRIANGLES, SQUARE_INDICES.len() as i32, gl::UNSIGNED_INT, ptr::null());
-                            x_pos += FONT_WIDTH;
-                        }
-                    }
-                }
+                let uniforms = GlyphUniforms {
+                    transform: transform_uniform_loc,
+                    tex_u:     tex_u_uniform_loc,
+                    tex_v:     tex_v_uniform_loc,
+                };
+                self.draw_lines(&strings, screen_height, &uniforms);
+                self.draw_lines(&status, FONT_HEIGHT * status.len() as f32, &uniforms);
 
                 gl::DisableVertexAttribArray(pos_attribute as GLuint);
                 gl::DisableVertexAttribArray(tex_attribute as GLuint);
@@ -593,6 +576,48 @@ impl Renderer {
         check_gl_error("rendering to window");
     }
 
+    //  Draws the lines downwards from the given height, expects the glyph program to be bound
+    unsafe fn draw_lines(&self, lines: &Vec<String>, top: f32, uniforms: &GlyphUniforms) {
+        let screen_width = self.x as f32;
+        let screen_height = self.y as f32;
+
+        let mut y_pos = top;
+        for text in lines {
+            //  New Line
+            y_pos -= FONT_HEIGHT;
+            let mut x_pos = 0.0;
+
+            let ascii = string_to_renderable(text.clone());
+            for byte in ascii {
+                if byte == 10 { /* /n */
+                    y_pos -= FONT_HEIGHT;
+                    x_pos = 0.0;
+                } else if byte == 9 { /* /t */
+                    x_pos += 4.0;
+                } else {
+                    let (tex_u, tex_v) = get_glyph_position(byte);
+                    gl::Uniform1f(uniforms.tex_u, tex_u);
+                    gl::Uniform1f(uniforms.tex_v, tex_v);
+
+                    let transform = cgmath::Matrix3::<f32>::new(
+                        2.0 * FONT_WIDTH / screen_width, 0.0, 0.0,
+                        0.0, 2.0 * FONT_HEIGHT / screen_height, 0.0,
+                        (2.0 * x_pos + FONT_WIDTH - screen_width) / screen_width, (2.0 * y_pos + FONT_HEIGHT - screen_height) / screen_height, 1.0
+                    );
+                    gl::UniformMatrix3fv(
+                        uniforms.transform,
+                        1,
+                        gl::FALSE,
+                        mem::transmute(&transform)
+                    );
+
+                    gl::DrawElements(gl::TRIANGLES, SQUARE_INDICES.len() as i32, gl::UNSIGNED_INT, ptr::null());
+                    x_pos += FONT_WIDTH;
+                }
+            }
+        }
+    }
+
     pub fn get_id(&self, size: (usize, usize)) -> Option<u32> {
         match self.framebuffer {
             Some(framebuffer) => {
@@ -0,0 +1,227 @@
+use types::message::{MessageIn, FrameKind, Object, value_as_u32};
+use types::ObjectRenderInfo;
+use visualization::configuration::Configuration;
+
+use std::collections::{HashMap, BTreeMap};
+
+#[derive(Clone)]
+pub struct Scene {
+    last_message_id: Option<String>,
+    objects:         HashMap<u32, Object>,
+    render_info:     BTreeMap<u32, ObjectRenderInfo>,
+    anonymous_info:  Vec<ObjectRenderInfo>,
+    permanent_info:  BTreeMap<u32, ObjectRenderInfo>,
+}
+
+impl Scene {
+    pub fn new() -> Scene {
+        Scene {
+            last_message_id: None,
+            objects:         HashMap::<u32, Object>::new(),
+            render_info:     BTreeMap::<u32, ObjectRenderInfo>::new(),
+            anonymous_info:  vec![],
+            permanent_info:  BTreeMap::<u32, ObjectRenderInfo>::new(),
+        }
+    }
+
+    pub fn apply(&mut self, msg: &MessageIn, configuration: &Configuration) {
+        if msg.new_game {
+            self.objects.clear();
+            self.render_info.clear();
+            self.anonymous_info.clear();
+            self.permanent_info.clear();
+        }
+
+        if msg.clear_permanent {
+            self.permanent_info.clear();
+        }
+        for id in &msg.removed_permanent {
+            let _ = self.permanent_info.remove(id);
+        }
+
+        match msg.kind {
+            FrameKind::Keyframe => {
+                self.objects.clear();
+                self.render_info.clear();
+                self.anonymous_info.clear();
+
+                for object in &msg.objects {
+                    self.insert(object.clone(), configuration);
+                }
+            },
+            FrameKind::Delta    => {
+                for id in &msg.removed {
+                    self.objects.remove(id);
+                    self.render_info.remove(id);
+                }
+
+                for object in &msg.objects {
+                    let id = object.get("id").and_then(value_as_u32);
+                    let merged = match id.and_then(|id| self.objects.remove(&id)) {
+                        Some(mut existing) => {
+                            existing.extend(object.clone());
+                            existing
+                        },
+                        None               => object.clone(),
+                    };
+                    self.insert(merged, configuration);
+                }
+            },
+        }
+
+        self.last_message_id = Some(msg.id.clone());
+    }
+
+    fn insert(&mut self, object: Object, configuration: &Configuration) {
+        let info = configuration.get_render_info(&object);
+        let id = info.id;
+
+        match info.permanent_id {
+            Some(permanent_id)             => {
+                let _ = self.render_info.remove(&id);
+                let _ = self.permanent_info.insert(permanent_id, info);
+            },
+            None if id == u32::max_value() => self.anonymous_info.push(info),
+            None                           => { let _ = self.render_info.insert(id, info); },
+        }
+
+        if id != u32::max_value() {
+            let _ = self.objects.insert(id, object);
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Object> {
+        self.objects.get(&id)
+    }
+
+    pub fn objects(&self) -> &HashMap<u32, Object> {
+        &self.objects
+    }
+
+    pub fn last_message_id(&self) -> Option<String> {
+        self.last_message_id.clone()
+    }
+
+    pub fn frame_id(&self) -> Option<&str> {
+        self.last_message_id.as_ref().map(|id| id.as_str())
+    }
+
+    //  Kept sorted by id, so the draw order doesn't change between frames
+    pub fn render_info(&self) -> &BTreeMap<u32, ObjectRenderInfo> {
+        &self.render_info
+    }
+
+    //  Objects without an id, in the order they were sent
+    pub fn anonymous_info(&self) -> &Vec<ObjectRenderInfo> {
+        &self.anonymous_info
+    }
+
+    pub fn permanent_info(&self) -> &BTreeMap<u32, ObjectRenderInfo> {
+        &self.permanent_info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::json::Json;
+
+    fn configuration() -> Configuration {
+        Configuration::new(String::from("missing.conf"))
+    }
+
+    fn frame(text: &str) -> MessageIn {
+        MessageIn::from_json(Json::from_str(text).unwrap(), Some(&String::from("p"))).unwrap()
+    }
+
+    fn scene(frames: &[&str], configuration: &Configuration) -> Scene {
+        let mut scene = Scene::new();
+        for text in frames {
+            scene.apply(&frame(text), configuration);
+        }
+        scene
+    }
+
+    fn ids<T>(info: &BTreeMap<u32, T>) -> Vec<u32> {
+        info.keys().cloned().collect()
+    }
+
+    #[test]
+    fn keyframe_splits_the_objects() {
+        let configuration = configuration();
+        let scene = scene(&[
+            r#"{"id":1,"objects":[{"id":3,"x":1},{"id":2},{"x":5},{"permanent_id":7,"id":4}]}"#,
+        ], &configuration);
+
+        assert_eq!(ids(scene.render_info()), vec![2, 3]);
+        assert_eq!(scene.anonymous_info().len(), 1);
+        assert_eq!(ids(scene.permanent_info()), vec![7]);
+        assert_eq!(scene.frame_id(), Some("1"));
+        assert!(scene.get(4).is_some());
+    }
+
+    #[test]
+    fn keyframe_replaces_the_scene() {
+        let configuration = configuration();
+        let scene = scene(&[
+            r#"{"id":1,"objects":[{"id":1},{"id":2},{"x":5},{"permanent_id":7}]}"#,
+            r#"{"id":2,"objects":[{"id":3}]}"#,
+        ], &configuration);
+
+        assert_eq!(ids(scene.render_info()), vec![3]);
+        assert!(scene.anonymous_info().is_empty());
+        assert_eq!(ids(scene.permanent_info()), vec![7]);
+        assert!(scene.get(1).is_none());
+    }
+
+    #[test]
+    fn delta_updates_one_attribute() {
+        let configuration = configuration();
+        let scene = scene(&[
+            r#"{"id":1,"objects":[{"id":1,"x":1,"y":2,"type":"a"},{"id":2,"x":3}]}"#,
+            r#"{"id":2,"kind":"delta","objects":[{"id":1,"x":4}]}"#,
+        ], &configuration);
+
+        let object = scene.get(1).unwrap();
+        assert_eq!(object.get("x"), Some(&Json::U64(4)));
+        assert_eq!(object.get("y"), Some(&Json::U64(2)));
+        assert_eq!(object.get("type"), Some(&Json::String(String::from("a"))));
+        assert_eq!(scene.render_info()[&1].position, (4.0, 2.0, 0.0));
+        assert_eq!(scene.render_info()[&2].position, (3.0, 0.0, 0.0));
+        assert_eq!(scene.frame_id(), Some("2"));
+    }
+
+    #[test]
+    fn delta_removes_ids() {
+        let configuration = configuration();
+        let scene = scene(&[
+            r#"{"id":1,"objects":[{"id":1},{"id":2},{"permanent_id":7},{"permanent_id":8}]}"#,
+            r#"{"id":2,"kind":"delta","removed":[1],"removed_permanent":[7]}"#,
+        ], &configuration);
+
+        assert_eq!(ids(scene.render_info()), vec![2]);
+        assert!(scene.get(1).is_none());
+        assert_eq!(ids(scene.permanent_info()), vec![8]);
+
+        let scene = self::scene(&[
+            r#"{"id":1,"objects":[{"id":1},{"permanent_id":7}]}"#,
+            r#"{"id":2,"kind":"delta","clear_permanent":true}"#,
+        ], &configuration);
+        assert_eq!(ids(scene.render_info()), vec![1]);
+        assert!(scene.permanent_info().is_empty());
+    }
+
+    #[test]
+    fn new_game_resets_everything() {
+        let configuration = configuration();
+        let scene = scene(&[
+            r#"{"id":1,"objects":[{"id":1},{"x":5},{"permanent_id":7}]}"#,
+            r#"{"id":2,"kind":"delta","new_game":true,"objects":[{"id":2}]}"#,
+        ], &configuration);
+
+        assert_eq!(ids(scene.render_info()), vec![2]);
+        assert!(scene.anonymous_info().is_empty());
+        assert!(scene.permanent_info().is_empty());
+        assert_eq!(scene.objects().len(), 1);
+    }
+}